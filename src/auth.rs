@@ -0,0 +1,102 @@
+use anyhow::{Error, anyhow};
+use cln_plugin::Plugin;
+use serde_json::json;
+
+use crate::{
+    keys::{linking_key, sign_k1},
+    lnurl::{build_client, fetch_lnurl_json, lnurl_to_url},
+    structs::{LnurlTag, PluginState},
+};
+
+pub async fn auth_lnurl(
+    plugin: Plugin<PluginState>,
+    lnurl: &str,
+) -> Result<serde_json::Value, Error> {
+    let (auth_url, tag) = lnurl_to_url(lnurl)?;
+    if let Some(t) = tag {
+        if t != LnurlTag::Login {
+            return Err(anyhow!("LNURL is a {t} and not a login: {lnurl}"));
+        }
+    }
+
+    let login_url =
+        url::Url::parse(&auth_url).map_err(|e| anyhow!("LNURL: invalid login url: {e}"))?;
+    let (k1, action) = parse_login_query(&login_url)?;
+    let domain = login_url
+        .host_str()
+        .ok_or_else(|| anyhow!("LNURL: login url has no host: {auth_url}"))?
+        .to_lowercase();
+
+    let key = linking_key(plugin.clone(), &domain).await?;
+    let (pubkey, sig) = sign_k1(&key, &k1)?;
+    let mut callback_url = login_url.clone();
+    callback_url
+        .query_pairs_mut()
+        .append_pair("sig", &sig)
+        .append_pair("key", &pubkey);
+
+    let config = plugin.state().config.lock().clone();
+    let client = build_client(&config)?;
+    fetch_lnurl_json(&client, callback_url.as_str(), "login").await?;
+
+    Ok(json!({
+        "domain": domain,
+        "key": pubkey,
+        "action": action,
+    }))
+}
+
+// LUD-04: the login url carries tag=login, a 32 byte hex k1 and
+// optionally one of the actions register, login, link or auth
+fn parse_login_query(auth_url: &url::Url) -> Result<(String, Option<String>), Error> {
+    let mut tag = None;
+    let mut k1 = None;
+    let mut action = None;
+    for (key, value) in auth_url.query_pairs() {
+        match key.as_ref() {
+            "tag" => tag = Some(value.into_owned()),
+            "k1" => k1 = Some(value.into_owned()),
+            "action" => action = Some(value.into_owned()),
+            _ => (),
+        }
+    }
+    match tag {
+        Some(t) if t.eq_ignore_ascii_case("login") => (),
+        Some(t) => return Err(anyhow!("LNURL is a {t} and not a login")),
+        None => return Err(anyhow!("LNURL login url is missing `tag=login`")),
+    }
+    let k1 = k1.ok_or_else(|| anyhow!("LNURL login url is missing `k1`"))?;
+    if let Some(a) = &action {
+        if !["register", "login", "link", "auth"].contains(&a.as_str()) {
+            return Err(anyhow!("LNURL login has unknown action: {a}"));
+        }
+    }
+    Ok((k1, action))
+}
+
+#[test]
+fn test_parse_login_query() {
+    let k1 = "e2af6254a8df433264fa23f67eb8188635d15ce883e8fc020989d5f82ae6f11e";
+    let (parsed_k1, action) = parse_login_query(
+        &url::Url::parse(&format!(
+            "https://site.com/auth?tag=login&k1={k1}&action=register"
+        ))
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(parsed_k1, k1);
+    assert_eq!(action, Some("register".to_owned()));
+    assert!(
+        parse_login_query(&url::Url::parse(&format!("https://site.com/auth?k1={k1}")).unwrap())
+            .is_err()
+    );
+    assert!(
+        parse_login_query(
+            &url::Url::parse(&format!(
+                "https://site.com/auth?tag=login&k1={k1}&action=hack"
+            ))
+            .unwrap()
+        )
+        .is_err()
+    );
+}
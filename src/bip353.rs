@@ -0,0 +1,432 @@
+use std::path::Path;
+
+use anyhow::{Error, anyhow};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::Utc;
+use cln_plugin::Plugin;
+use cln_rpc::{ClnRpc, model::requests::DecodeRequest, primitives::Amount};
+use dnssec_prover::{
+    query::{ProofBuilder, QueryBuf},
+    rr::{Name, RR, TXT_TYPE},
+    ser::parse_rr_stream,
+    validation::{VerifiedRRStream, verify_rr_stream},
+};
+use serde_json::{Map, json};
+
+use crate::{
+    lnurl::build_client,
+    structs::{Bip353Resolution, Config, PluginState, Resolution},
+    util::strip_prefix_ignore_ascii_case,
+};
+
+// BIP-353: user@domain publishes a bitcoin: uri in a DNSSEC signed TXT record at
+// user.user._bitcoin-payment.domain. The RFC 9102 proof for it is built via
+// DNS-over-HTTPS and verified locally, so no resolver has to be trusted. The
+// offer replaces the address so every pay command can handle it.
+pub async fn resolve_bip353(
+    plugin: Plugin<PluginState>,
+    invstring_name: &str,
+    address: &str,
+    amount_msat: Option<Amount>,
+    params: &mut Map<String, serde_json::Value>,
+) -> Result<Resolution, Error> {
+    let config = plugin.state().config.lock().clone();
+    let name = bip353_name(address)?;
+    let proof = fetch_bip353_proof(&config, &name).await?;
+    let now = u64::try_from(Utc::now().timestamp())?;
+    let bip353 = verify_bip353_proof(&name, proof, now)?;
+    resolve_bip353_offer(plugin, invstring_name, address, bip353, amount_msat, params).await
+}
+
+// Checks the amount of the verified offer and puts it in place of the address
+pub async fn resolve_bip353_offer(
+    plugin: Plugin<PluginState>,
+    invstring_name: &str,
+    address: &str,
+    bip353: Bip353Resolution,
+    amount_msat: Option<Amount>,
+    params: &mut Map<String, serde_json::Value>,
+) -> Result<Resolution, Error> {
+    log::debug!("bip353 uri of {address}: {}", bip353.uri);
+
+    let mut rpc = ClnRpc::new(
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file),
+    )
+    .await?;
+    let decoded = rpc
+        .call_typed(&DecodeRequest {
+            string: bip353.offer.clone(),
+        })
+        .await
+        .map_err(|e| anyhow!("could not decode offer of {address}: {}", e.message))?;
+    if let Some(offer_amount) = decoded.offer_amount_msat {
+        if amount_msat.is_some_and(|a| a != offer_amount) {
+            return Err(anyhow!(
+                "offer of {address} is for {}msat and not for {}msat",
+                offer_amount.msat(),
+                amount_msat.unwrap().msat()
+            ));
+        }
+        params.remove("amount_msat");
+    }
+
+    params.insert(invstring_name.to_owned(), json!(bip353.offer));
+    Ok(Resolution {
+        bip353: Some(bip353),
+        ..Default::default()
+    })
+}
+
+pub fn bip353_name(address: &str) -> Result<Name, Error> {
+    let address = address.strip_prefix('₿').unwrap_or(address);
+    let (user, domain) = address
+        .split_once('@')
+        .ok_or_else(|| anyhow!("bip353 address invalid: {address}"))?;
+    if user.is_empty() || domain.is_empty() {
+        return Err(anyhow!("bip353 address invalid: {address}"));
+    }
+    let domain = domain.trim_end_matches('.');
+    Name::try_from(format!("{user}.user._bitcoin-payment.{domain}."))
+        .map_err(|()| anyhow!("bip353 address is not a valid dns name: {address}"))
+}
+
+// Tries the DoH servers in order, the proof is the same no matter who built it
+async fn fetch_bip353_proof(config: &Config, name: &Name) -> Result<Vec<u8>, Error> {
+    let client = build_client(config)?;
+    let mut errors = Vec::new();
+    for server in &config.doh_servers {
+        match build_proof_doh(&client, server, name).await {
+            Ok(proof) => return Ok(proof),
+            Err(e) => {
+                log::debug!("Building DNSSEC proof for {name} via {server} failed: {e}");
+                errors.push(format!("{server}: {e}"));
+            }
+        }
+    }
+    Err(anyhow!(
+        "could not build DNSSEC proof for {}: {}",
+        name.as_str(),
+        errors.join(", ")
+    ))
+}
+
+async fn build_proof_doh(
+    client: &reqwest::Client,
+    server: &str,
+    name: &Name,
+) -> Result<Vec<u8>, Error> {
+    let (mut builder, initial_query) = ProofBuilder::new(name, TXT_TYPE);
+    let mut pending_queries = vec![initial_query];
+    while builder.awaiting_responses() {
+        let query = pending_queries
+            .pop()
+            .ok_or_else(|| anyhow!("no queries left while building proof"))?;
+        let response = client
+            .get(format!("{server}?dns={}", URL_SAFE_NO_PAD.encode(&*query)))
+            .header("accept", "application/dns-message")
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("got bad status: {}", response.status()));
+        }
+        let response_bytes = response.bytes().await?;
+        let mut response_buf = QueryBuf::new_zeroed(0);
+        response_buf.extend_from_slice(&response_bytes);
+        pending_queries.extend(
+            builder
+                .process_response(&response_buf)
+                .map_err(|e| anyhow!("{e}"))?,
+        );
+    }
+    let (proof, _ttl) = builder
+        .finish_proof()
+        .map_err(|()| anyhow!("too many queries needed"))?;
+    Ok(proof)
+}
+
+pub fn verify_bip353_proof(
+    name: &Name,
+    proof: Vec<u8>,
+    now: u64,
+) -> Result<Bip353Resolution, Error> {
+    let rrs = parse_rr_stream(&proof).map_err(|()| anyhow!("DNSSEC proof is malformed"))?;
+    let verified = verify_rr_stream(&rrs).map_err(|e| anyhow!("DNSSEC proof is invalid: {e:?}"))?;
+    bip353_from_verified(name, &verified, proof, now)
+}
+
+// dnssec-prover only trusts the DNS root keys, so everything after the signature
+// checks is kept apart to be tested with zones built by the tests themselves
+fn bip353_from_verified(
+    name: &Name,
+    verified: &VerifiedRRStream,
+    proof: Vec<u8>,
+    now: u64,
+) -> Result<Bip353Resolution, Error> {
+    if now < verified.valid_from || now > verified.expires {
+        return Err(anyhow!(
+            "DNSSEC proof is only valid from {} until {}",
+            verified.valid_from,
+            verified.expires
+        ));
+    }
+
+    // Only TXT records starting with bitcoin: are payment instructions
+    let mut uris = verified
+        .resolve_name(name)
+        .into_iter()
+        .filter_map(|rr| match rr {
+            RR::Txt(txt) => String::from_utf8(txt.data.as_vec()).ok(),
+            _ => None,
+        })
+        .filter(|txt| strip_prefix_ignore_ascii_case(txt, "bitcoin:").is_some());
+    let uri = uris
+        .next()
+        .ok_or_else(|| anyhow!("no bitcoin: payment instructions in TXT records"))?;
+    if uris.next().is_some() {
+        return Err(anyhow!("more than one bitcoin: TXT record"));
+    }
+    let offer = offer_from_uri(&uri)?;
+
+    Ok(Bip353Resolution {
+        uri,
+        offer,
+        valid_from: verified.valid_from,
+        expires: verified.expires,
+        proof,
+    })
+}
+
+// BIP-21 uris must not be used if they have required (req-) parameters we don't know
+fn offer_from_uri(uri: &str) -> Result<String, Error> {
+    let Some((_address, query)) = uri.split_once('?') else {
+        return Err(anyhow!("bip353 uri has no offer: {uri}"));
+    };
+    let mut offer = None;
+    for param in query.split('&') {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        if key.eq_ignore_ascii_case("lno") {
+            offer = Some(value.to_owned());
+        } else if strip_prefix_ignore_ascii_case(key, "req-").is_some() {
+            return Err(anyhow!("bip353 uri has unknown required parameter `{key}`"));
+        }
+    }
+    offer.ok_or_else(|| anyhow!("bip353 uri has no offer: {uri}"))
+}
+
+#[test]
+fn test_bip353_name() {
+    assert_eq!(
+        bip353_name("matt@mattcorallo.com").unwrap().as_str(),
+        "matt.user._bitcoin-payment.mattcorallo.com."
+    );
+    assert_eq!(
+        bip353_name("₿matt@mattcorallo.com.").unwrap().as_str(),
+        "matt.user._bitcoin-payment.mattcorallo.com."
+    );
+    assert!(bip353_name("mattcorallo.com").is_err());
+    assert!(bip353_name("@mattcorallo.com").is_err());
+}
+
+#[test]
+fn test_offer_from_uri() {
+    assert_eq!(offer_from_uri("bitcoin:?lno=lno1qq").unwrap(), "lno1qq");
+    assert_eq!(
+        offer_from_uri("BITCOIN:bc1qx?amount=1&LNO=lno1qq&sp=sp1qq").unwrap(),
+        "lno1qq"
+    );
+    assert!(offer_from_uri("bitcoin:bc1qx").is_err());
+    assert!(offer_from_uri("bitcoin:bc1qx?sp=sp1qq").is_err());
+    assert!(offer_from_uri("bitcoin:?lno=lno1qq&req-pj=https://pj.com").is_err());
+}
+
+// A proof for matt@mattcorallo.com signed by the real DNS root, valid between
+// 1785988800 and 1786415920. It also has a TXT record that is not a bitcoin: uri.
+#[test]
+fn test_verify_bip353_proof() {
+    use bitcoin::hex::FromHex;
+
+    let proof = Vec::<u8>::from_hex(include_str!("../tests/data/bip353_proof.hex").trim()).unwrap();
+    let name = bip353_name("matt@mattcorallo.com").unwrap();
+
+    let verified = verify_bip353_proof(&name, proof.clone(), 1786000000).unwrap();
+    assert!(
+        verified
+            .uri
+            .starts_with("bitcoin:bc1qztwy6xen3zdtt7z0vrgapmjtfz8acjkfp5fp7l?lno=")
+    );
+    assert!(
+        verified
+            .offer
+            .starts_with("lno1zr5qyugqgskrk70kqmuq7v3dnr2fnmhukps9n8hut48vk")
+    );
+    assert_eq!(verified.valid_from, 1785988800);
+    assert_eq!(verified.expires, 1786415920);
+    assert_eq!(verified.proof, proof);
+
+    assert!(
+        verify_bip353_proof(&name, proof.clone(), 1786415921)
+            .unwrap_err()
+            .to_string()
+            .contains("only valid from")
+    );
+    let other_name = bip353_name("notmatt@mattcorallo.com").unwrap();
+    assert!(verify_bip353_proof(&other_name, proof.clone(), 1786000000).is_err());
+
+    let mut tampered = proof;
+    let last = tampered.len() - 1;
+    tampered[last] ^= 1;
+    assert!(verify_bip353_proof(&name, tampered, 1786000000).is_err());
+}
+
+// The real proof with forged records: rewriting the TXT record, breaking its
+// signature or dropping the signature of the root keys must not verify
+#[test]
+fn test_verify_bip353_proof_bad_signatures() {
+    use bitcoin::hex::FromHex;
+    use dnssec_prover::{
+        rr::{Txt, TxtBytes},
+        ser::write_rr,
+    };
+    // RFC 4034 type of DNSKEY records
+    const DNSKEY_TYPE: u16 = 48;
+
+    let proof = Vec::<u8>::from_hex(include_str!("../tests/data/bip353_proof.hex").trim()).unwrap();
+    let name = bip353_name("matt@mattcorallo.com").unwrap();
+    let rrs = parse_rr_stream(&proof).unwrap();
+    let serialize = |rrs: Vec<RR>| {
+        let mut out = Vec::new();
+        for rr in &rrs {
+            write_rr(rr, 3600, &mut out);
+        }
+        out
+    };
+    let verify = |rrs: Vec<RR>| verify_bip353_proof(&name, serialize(rrs), 1786000000);
+    assert!(verify(rrs.clone()).is_ok());
+
+    let forged_txt = rrs
+        .iter()
+        .cloned()
+        .map(|rr| match rr {
+            RR::Txt(txt) if txt.name == name => RR::Txt(Txt {
+                name: txt.name,
+                data: TxtBytes::new(b"bitcoin:?lno=lno1attacker").unwrap(),
+            }),
+            rr => rr,
+        })
+        .collect();
+    assert!(
+        verify(forged_txt)
+            .unwrap_err()
+            .to_string()
+            .contains("DNSSEC proof is invalid")
+    );
+
+    let broken_signature = rrs
+        .iter()
+        .cloned()
+        .map(|rr| match rr {
+            RR::RRSig(mut sig) if sig.ty == TXT_TYPE && sig.name == name => {
+                sig.signature[0] ^= 1;
+                RR::RRSig(sig)
+            }
+            rr => rr,
+        })
+        .collect();
+    assert!(
+        verify(broken_signature)
+            .unwrap_err()
+            .to_string()
+            .contains("DNSSEC proof is invalid")
+    );
+
+    let unsigned_root = rrs
+        .into_iter()
+        .filter(
+            |rr| !matches!(rr, RR::RRSig(sig) if sig.ty == DNSKEY_TYPE && sig.name.as_str() == "."),
+        )
+        .collect();
+    assert!(verify(unsigned_root).is_err());
+}
+
+// Zones of the tests' own, as they are after their signatures were checked:
+// CNAMEs are followed, other names and non bitcoin: TXT records are ignored
+// and the validity window comes from the signatures
+#[test]
+fn test_bip353_from_verified() {
+    use dnssec_prover::rr::{CName, Txt, TxtBytes};
+
+    let name = bip353_name("alice@example.com").unwrap();
+    let txt = |name: &Name, data: &str| {
+        RR::Txt(Txt {
+            name: name.clone(),
+            data: TxtBytes::new(data.as_bytes()).unwrap(),
+        })
+    };
+    let verify = |rrs: &[RR], now: u64| {
+        let verified = VerifiedRRStream {
+            verified_rrs: rrs.iter().collect(),
+            valid_from: 1_700_000_000,
+            expires: 1_700_086_400,
+            max_cache_ttl: 3600,
+        };
+        bip353_from_verified(&name, &verified, vec![1, 2, 3], now)
+    };
+    let now = 1_700_000_100;
+
+    let zone = [
+        txt(&name, "v=spf1 -all"),
+        txt(&name, "bitcoin:?lno=lno1alice"),
+        txt(
+            &bip353_name("bob@example.com").unwrap(),
+            "bitcoin:?lno=lno1bob",
+        ),
+    ];
+    let resolved = verify(&zone, now).unwrap();
+    assert_eq!(resolved.uri, "bitcoin:?lno=lno1alice");
+    assert_eq!(resolved.offer, "lno1alice");
+    assert_eq!(resolved.valid_from, 1_700_000_000);
+    assert_eq!(resolved.expires, 1_700_086_400);
+    assert_eq!(resolved.proof, vec![1, 2, 3]);
+
+    assert!(
+        verify(&zone, 1_699_999_999)
+            .unwrap_err()
+            .to_string()
+            .contains("only valid from 1700000000 until 1700086400")
+    );
+    assert!(
+        verify(&zone, 1_700_086_401)
+            .unwrap_err()
+            .to_string()
+            .contains("only valid from")
+    );
+
+    let target = Name::try_from("pay.example.net.").unwrap();
+    let cname_zone = [
+        RR::CName(CName {
+            name: name.clone(),
+            canonical_name: target.clone(),
+        }),
+        txt(&target, "bitcoin:?lno=lno1target"),
+    ];
+    assert_eq!(verify(&cname_zone, now).unwrap().offer, "lno1target");
+
+    assert!(
+        verify(&[txt(&name, "v=spf1 -all")], now)
+            .unwrap_err()
+            .to_string()
+            .contains("no bitcoin: payment instructions")
+    );
+    assert!(
+        verify(
+            &[
+                txt(&name, "bitcoin:?lno=lno1alice"),
+                txt(&name, "BITCOIN:?lno=lno1mallory")
+            ],
+            now
+        )
+        .unwrap_err()
+        .to_string()
+        .contains("more than one bitcoin: TXT record")
+    );
+}
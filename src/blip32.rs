@@ -0,0 +1,571 @@
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use anyhow::{Error, anyhow};
+use bitcoin::{
+    hashes::{Hash, HashEngine, Hmac, HmacEngine, sha256},
+    hex::{DisplayHex, FromHex},
+    secp256k1::{All, PublicKey, Scalar, Secp256k1, SecretKey, ecdh::SharedSecret},
+};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce, aead::Aead};
+use chrono::Utc;
+use cln_plugin::Plugin;
+use cln_rpc::{
+    ClnRpc,
+    model::requests::{
+        ConnectRequest,
+        CreateonionHops,
+        CreateonionRequest,
+        GetinfoRequest,
+        InjectonionmessageRequest,
+        ListnodesRequest,
+        MakesecretRequest,
+    },
+    primitives::Amount,
+};
+use dnssec_prover::rr::Name;
+use parking_lot::Mutex;
+use serde_json::Map;
+use tokio::sync::oneshot;
+
+use crate::{
+    bip353::{bip353_name, resolve_bip353_offer, verify_bip353_proof},
+    structs::{DnssecReply, PluginState, Resolution},
+};
+
+// bLIP-32 onion message fields
+const TLV_DNSSEC_QUERY: u64 = 65536;
+const TLV_DNSSEC_PROOF: u64 = 65538;
+// onionmsg_tlv and encrypted_data_tlv fields of BOLT 4
+const TLV_REPLY_PATH: u64 = 2;
+const TLV_ENCRYPTED_RECIPIENT_DATA: u64 = 4;
+const TLV_NEXT_NODE_ID: u64 = 4;
+const TLV_PATH_ID: u64 = 6;
+// node_announcement feature bits of bLIP-32 resolvers
+const DNS_RESOLVER_FEATURES: [usize; 2] = [258, 259];
+// Resolvers found in gossip that are asked before giving up
+const MAX_GOSSIP_RESOLVERS: usize = 3;
+
+struct BlindedHop {
+    blinded_node_id: PublicKey,
+    encrypted_data: Vec<u8>,
+}
+
+// The onion of a `dnssec_query` and what is needed to send it and to match its reply
+struct DnssecQuery {
+    path_key: PublicKey,
+    hops: Vec<CreateonionHops>,
+    path_id: [u8; 32],
+}
+
+// bLIP-32: the BIP-353 name is sent to a resolver node in a `dnssec_query` onion
+// message and the resolver answers with a `dnssec_proof` through our reply path.
+// The proof is verified locally like the DNS-over-HTTPS one, but neither a DNS
+// nor a HTTP provider learns the domain of the payee.
+pub async fn resolve_blip32(
+    plugin: Plugin<PluginState>,
+    invstring_name: &str,
+    address: &str,
+    amount_msat: Option<Amount>,
+    params: &mut Map<String, serde_json::Value>,
+) -> Result<Resolution, Error> {
+    let name = bip353_name(address)?;
+    let mut rpc = ClnRpc::new(
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file),
+    )
+    .await?;
+    let our_id = rpc.call_typed(&GetinfoRequest {}).await?.id;
+    let resolvers = dns_resolvers(plugin.clone(), &mut rpc, our_id).await?;
+
+    let mut errors = Vec::new();
+    for resolver in resolvers {
+        let proof = match query_resolver(plugin.clone(), &mut rpc, our_id, resolver, &name).await {
+            Ok(o) => o,
+            Err(e) => {
+                log::debug!("Querying {resolver} for {} failed: {e}", name.as_str());
+                errors.push(format!("{resolver}: {e}"));
+                continue;
+            }
+        };
+        let now = u64::try_from(Utc::now().timestamp())?;
+        match verify_bip353_proof(&name, proof, now) {
+            Ok(bip353) => {
+                log::debug!("{resolver} resolved {}", name.as_str());
+                return resolve_bip353_offer(
+                    plugin,
+                    invstring_name,
+                    address,
+                    bip353,
+                    amount_msat,
+                    params,
+                )
+                .await;
+            }
+            Err(e) => errors.push(format!("{resolver}: {e}")),
+        }
+    }
+    Err(anyhow!(
+        "no dns resolver answered for {}: {}",
+        name.as_str(),
+        errors.join(", ")
+    ))
+}
+
+// Configured resolvers are used as they are, otherwise nodes announcing the
+// bLIP-32 feature are taken from gossip
+async fn dns_resolvers(
+    plugin: Plugin<PluginState>,
+    rpc: &mut ClnRpc,
+    our_id: PublicKey,
+) -> Result<Vec<PublicKey>, Error> {
+    let configured = plugin.state().config.lock().dns_resolvers.clone();
+    if !configured.is_empty() {
+        return Ok(configured);
+    }
+    let resolvers: Vec<PublicKey> = rpc
+        .call_typed(&ListnodesRequest { id: None })
+        .await?
+        .nodes
+        .into_iter()
+        .filter(|n| n.nodeid != our_id)
+        .filter(|n| {
+            n.features.as_deref().is_some_and(|f| {
+                DNS_RESOLVER_FEATURES
+                    .iter()
+                    .any(|bit| has_feature_bit(f, *bit))
+            })
+        })
+        .map(|n| n.nodeid)
+        .take(MAX_GOSSIP_RESOLVERS)
+        .collect();
+    if resolvers.is_empty() {
+        return Err(anyhow!("no dns resolver configured or found in gossip"));
+    }
+    Ok(resolvers)
+}
+
+// Feature bits are big endian, bit 0 is the lowest bit of the last byte
+fn has_feature_bit(features: &str, bit: usize) -> bool {
+    let Ok(features) = Vec::<u8>::from_hex(features) else {
+        return false;
+    };
+    let index = bit / 8;
+    index < features.len() && features[features.len() - 1 - index] & (1 << (bit % 8)) != 0
+}
+
+// We connect to the resolver so the query and its reply each take one hop
+async fn query_resolver(
+    plugin: Plugin<PluginState>,
+    rpc: &mut ClnRpc,
+    our_id: PublicKey,
+    resolver: PublicKey,
+    name: &Name,
+) -> Result<Vec<u8>, Error> {
+    rpc.call_typed(&ConnectRequest {
+        id: resolver.to_string(),
+        host: None,
+        port: None,
+    })
+    .await
+    .map_err(|e| anyhow!("could not connect: {}", e.message))?;
+
+    // The path keys and the path_id must not be guessable by anyone else
+    let secret = rpc
+        .call_typed(&MakesecretRequest {
+            hex: None,
+            string: Some(format!(
+                "payany dnssec_query {} {resolver} {}",
+                name.as_str(),
+                Utc::now().timestamp_nanos_opt().unwrap_or_default()
+            )),
+        })
+        .await?
+        .secret;
+    let query = dnssec_query_onion(&Secp256k1::new(), our_id, resolver, name, &secret.to_vec())?;
+
+    let onion = rpc
+        .call_typed(&CreateonionRequest {
+            hops: query.hops,
+            assocdata: String::new(),
+            session_key: None,
+            onion_size: None,
+        })
+        .await
+        .map_err(|e| anyhow!("could not create onion: {}", e.message))?
+        .onion;
+
+    let timeout = plugin.state().config.lock().dns_resolver_timeout;
+    let (sender, receiver) = oneshot::channel();
+    plugin
+        .state()
+        .dnssec_queries
+        .lock()
+        .insert(query.path_id, sender);
+    let reply = match rpc
+        .call_typed(&InjectonionmessageRequest {
+            message: onion,
+            path_key: query.path_key,
+        })
+        .await
+    {
+        Ok(_) => tokio::time::timeout(Duration::from_secs(timeout), receiver).await,
+        Err(e) => {
+            plugin.state().dnssec_queries.lock().remove(&query.path_id);
+            return Err(anyhow!("could not send dnssec_query: {}", e.message));
+        }
+    };
+    plugin.state().dnssec_queries.lock().remove(&query.path_id);
+
+    let dnssec_proof = reply
+        .map_err(|_| anyhow!("no dnssec_proof within {timeout}s"))?
+        .map_err(|_| anyhow!("dnssec_query was dropped"))?;
+    parse_dnssec_proof(&dnssec_proof, name)
+}
+
+// The query travels us -> resolver, so our own node can forward it like any
+// other onion message. The reply path only has us in it and the path_id tells
+// us which query is answered.
+fn dnssec_query_onion(
+    secp: &Secp256k1<All>,
+    our_id: PublicKey,
+    resolver: PublicKey,
+    name: &Name,
+    secret: &[u8],
+) -> Result<DnssecQuery, Error> {
+    let forward_key = SecretKey::from_slice(&tagged_hash(b"forward", secret))?;
+    let reply_key = SecretKey::from_slice(&tagged_hash(b"reply", secret))?;
+    let path_id = tagged_hash(b"path_id", secret);
+
+    let mut reply_data = Vec::new();
+    write_tlv(&mut reply_data, TLV_PATH_ID, &path_id);
+    let reply_hops = blind_path(secp, reply_key, &[(our_id, reply_data)])?;
+    let mut reply_path = Vec::new();
+    reply_path.extend(our_id.serialize());
+    reply_path.extend(PublicKey::from_secret_key(secp, &reply_key).serialize());
+    reply_path.push(u8::try_from(reply_hops.len())?);
+    for hop in &reply_hops {
+        reply_path.extend(hop.blinded_node_id.serialize());
+        reply_path.extend(u16::try_from(hop.encrypted_data.len())?.to_be_bytes());
+        reply_path.extend(&hop.encrypted_data);
+    }
+
+    let mut query = vec![u8::try_from(name.as_str().len())?];
+    query.extend(name.as_str().as_bytes());
+
+    let mut forward_data = Vec::new();
+    write_tlv(&mut forward_data, TLV_NEXT_NODE_ID, &resolver.serialize());
+    let forward_hops = blind_path(
+        secp,
+        forward_key,
+        &[(our_id, forward_data), (resolver, Vec::new())],
+    )?;
+    let last = forward_hops.len() - 1;
+    let hops = forward_hops
+        .into_iter()
+        .enumerate()
+        .map(|(i, hop)| {
+            let mut payload = Vec::new();
+            if i == last {
+                write_tlv(&mut payload, TLV_REPLY_PATH, &reply_path);
+            }
+            write_tlv(
+                &mut payload,
+                TLV_ENCRYPTED_RECIPIENT_DATA,
+                &hop.encrypted_data,
+            );
+            if i == last {
+                write_tlv(&mut payload, TLV_DNSSEC_QUERY, &query);
+            }
+            // createonion wants the payloads prefixed with their length
+            let mut prefixed = Vec::new();
+            write_bigsize(&mut prefixed, payload.len() as u64);
+            prefixed.extend(payload);
+            CreateonionHops {
+                pubkey: hop.blinded_node_id,
+                payload: prefixed.to_lower_hex_string(),
+            }
+        })
+        .collect();
+
+    Ok(DnssecQuery {
+        path_key: PublicKey::from_secret_key(secp, &forward_key),
+        hops,
+        path_id,
+    })
+}
+
+// BOLT 4 route blinding: every node id is blinded and its data encrypted with the
+// secret it shares with the path key, which is tweaked for the next hop
+fn blind_path(
+    secp: &Secp256k1<All>,
+    session_key: SecretKey,
+    hops: &[(PublicKey, Vec<u8>)],
+) -> Result<Vec<BlindedHop>, Error> {
+    let mut key = session_key;
+    let mut blinded_hops = Vec::new();
+    for (node_id, data) in hops {
+        let path_key = PublicKey::from_secret_key(secp, &key);
+        let shared_secret = SharedSecret::new(node_id, &key).secret_bytes();
+        let blinded_node_id = node_id.mul_tweak(
+            secp,
+            &Scalar::from_be_bytes(hmac256(b"blinded_node_id", &shared_secret))?,
+        )?;
+        let rho = hmac256(b"rho", &shared_secret);
+        let encrypted_data = ChaCha20Poly1305::new(Key::from_slice(&rho))
+            .encrypt(Nonce::from_slice(&[0; 12]), data.as_slice())
+            .map_err(|_| anyhow!("could not encrypt blinded path data"))?;
+        blinded_hops.push(BlindedHop {
+            blinded_node_id,
+            encrypted_data,
+        });
+
+        let mut engine = sha256::Hash::engine();
+        engine.input(&path_key.serialize());
+        engine.input(&shared_secret);
+        key = key.mul_tweak(&Scalar::from_be_bytes(
+            sha256::Hash::from_engine(engine).to_byte_array(),
+        )?)?;
+    }
+    Ok(blinded_hops)
+}
+
+// The path_id of the reply path is the secret of the onion message and the
+// dnssec_proof is an unknown field to CLN
+pub fn dnssec_proof_reply(onion_message: &serde_json::Value) -> Option<([u8; 32], Vec<u8>)> {
+    let path_id = onion_message["pathsecret"]
+        .as_str()
+        .and_then(|p| <[u8; 32]>::from_hex(p).ok())?;
+    let dnssec_proof = onion_message["unknown_fields"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|f| f["number"].as_u64() == Some(TLV_DNSSEC_PROOF))
+        .and_then(|f| f["value"].as_str())
+        .and_then(|v| Vec::<u8>::from_hex(v).ok())?;
+    Some((path_id, dnssec_proof))
+}
+
+pub fn deliver_dnssec_proof(
+    dnssec_queries: &Mutex<HashMap<[u8; 32], DnssecReply>>,
+    path_id: [u8; 32],
+    dnssec_proof: Vec<u8>,
+) {
+    let Some(sender) = dnssec_queries.lock().remove(&path_id) else {
+        log::debug!("Got dnssec_proof for an unknown or expired query");
+        return;
+    };
+    if sender.send(dnssec_proof).is_err() {
+        log::debug!("dnssec_proof arrived after its query was given up");
+    }
+}
+
+// dnssec_proof: [u8 name_len][name][u16 proof_len][proof]
+fn parse_dnssec_proof(dnssec_proof: &[u8], name: &Name) -> Result<Vec<u8>, Error> {
+    let malformed = || anyhow!("dnssec_proof is malformed");
+    let (name_len, rest) = dnssec_proof.split_first().ok_or_else(malformed)?;
+    if rest.len() < usize::from(*name_len) + 2 {
+        return Err(malformed());
+    }
+    let (proof_name, rest) = rest.split_at(usize::from(*name_len));
+    if !proof_name.eq_ignore_ascii_case(name.as_str().as_bytes()) {
+        return Err(anyhow!(
+            "dnssec_proof is for {} instead of {}",
+            String::from_utf8_lossy(proof_name),
+            name.as_str()
+        ));
+    }
+    let (proof_len, proof) = rest.split_at(2);
+    if usize::from(u16::from_be_bytes([proof_len[0], proof_len[1]])) != proof.len() {
+        return Err(malformed());
+    }
+    Ok(proof.to_vec())
+}
+
+fn tagged_hash(tag: &[u8], secret: &[u8]) -> [u8; 32] {
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag);
+    engine.input(secret);
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+fn hmac256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut engine = HmacEngine::<sha256::Hash>::new(key);
+    engine.input(data);
+    Hmac::<sha256::Hash>::from_engine(engine).to_byte_array()
+}
+
+fn write_bigsize(buf: &mut Vec<u8>, n: u64) {
+    if n < 0xfd {
+        buf.push(n as u8);
+    } else if let Ok(n) = u16::try_from(n) {
+        buf.push(0xfd);
+        buf.extend(n.to_be_bytes());
+    } else if let Ok(n) = u32::try_from(n) {
+        buf.push(0xfe);
+        buf.extend(n.to_be_bytes());
+    } else {
+        buf.push(0xff);
+        buf.extend(n.to_be_bytes());
+    }
+}
+
+fn write_tlv(buf: &mut Vec<u8>, tlv_type: u64, value: &[u8]) {
+    write_bigsize(buf, tlv_type);
+    write_bigsize(buf, value.len() as u64);
+    buf.extend(value);
+}
+
+#[test]
+fn test_has_feature_bit() {
+    assert!(has_feature_bit(
+        "080000000000000000000000000000000000000000000000000000000000000000",
+        259
+    ));
+    assert!(!has_feature_bit(
+        "080000000000000000000000000000000000000000000000000000000000000000",
+        258
+    ));
+    assert!(has_feature_bit("0100", 8));
+    assert!(!has_feature_bit("0100", 259));
+    assert!(!has_feature_bit("nothex", 0));
+}
+
+// Every hop must be able to find itself and read its data like a node would:
+// with its node key and the path key it received
+#[test]
+fn test_blind_path() {
+    let secp = Secp256k1::new();
+    let node_keys = [
+        SecretKey::from_slice(&[0x41; 32]).unwrap(),
+        SecretKey::from_slice(&[0x42; 32]).unwrap(),
+    ];
+    let session_key = SecretKey::from_slice(&[0x43; 32]).unwrap();
+    let hops: Vec<(PublicKey, Vec<u8>)> = node_keys
+        .iter()
+        .enumerate()
+        .map(|(i, k)| (PublicKey::from_secret_key(&secp, k), vec![i as u8; 5]))
+        .collect();
+    let blinded = blind_path(&secp, session_key, &hops).unwrap();
+
+    let mut path_key = PublicKey::from_secret_key(&secp, &session_key);
+    for ((node_key, (_, data)), hop) in node_keys.iter().zip(&hops).zip(&blinded) {
+        let shared_secret = SharedSecret::new(&path_key, node_key).secret_bytes();
+        let blinded_key = node_key
+            .mul_tweak(&Scalar::from_be_bytes(hmac256(b"blinded_node_id", &shared_secret)).unwrap())
+            .unwrap();
+        assert_eq!(
+            PublicKey::from_secret_key(&secp, &blinded_key),
+            hop.blinded_node_id
+        );
+        let decrypted = ChaCha20Poly1305::new(Key::from_slice(&hmac256(b"rho", &shared_secret)))
+            .decrypt(Nonce::from_slice(&[0; 12]), hop.encrypted_data.as_slice())
+            .unwrap();
+        assert_eq!(&decrypted, data);
+
+        let mut engine = sha256::Hash::engine();
+        engine.input(&path_key.serialize());
+        engine.input(&shared_secret);
+        path_key = path_key
+            .mul_tweak(
+                &secp,
+                &Scalar::from_be_bytes(sha256::Hash::from_engine(engine).to_byte_array()).unwrap(),
+            )
+            .unwrap();
+    }
+}
+
+#[test]
+fn test_dnssec_query_onion() {
+    let secp = Secp256k1::new();
+    let our_id = PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&[0x41; 32]).unwrap());
+    let resolver = PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&[0x42; 32]).unwrap());
+    let name = bip353_name("matt@mattcorallo.com").unwrap();
+    let query = dnssec_query_onion(&secp, our_id, resolver, &name, &[0x43; 32]).unwrap();
+
+    assert_eq!(query.hops.len(), 2);
+    // Ours only holds the encrypted next_node_id tlv and 16 bytes of tag
+    assert!(query.hops[0].payload.starts_with("350433"));
+    let payload = Vec::<u8>::from_hex(&query.hops[1].payload).unwrap();
+    let mut dnssec_query = Vec::new();
+    write_tlv(
+        &mut dnssec_query,
+        TLV_DNSSEC_QUERY,
+        b"\x2bmatt.user._bitcoin-payment.mattcorallo.com.",
+    );
+    assert!(payload.ends_with(&dnssec_query));
+    assert!(dnssec_query.starts_with(&[0xfe, 0x00, 0x01, 0x00, 0x00, 0x2c]));
+    assert_eq!(payload[0], u8::try_from(payload.len() - 1).unwrap());
+    // The reply path starts at our own node
+    assert_eq!(&payload[1..3], &[2, 152]);
+    assert_eq!(&payload[3..36], &our_id.serialize());
+}
+
+#[test]
+fn test_parse_dnssec_proof() {
+    let name = bip353_name("matt@mattcorallo.com").unwrap();
+    let mut dnssec_proof = vec![43];
+    dnssec_proof.extend(b"MATT.user._bitcoin-payment.mattcorallo.com.");
+    dnssec_proof.extend([0, 3, 1, 2, 3]);
+    assert_eq!(
+        parse_dnssec_proof(&dnssec_proof, &name).unwrap(),
+        vec![1, 2, 3]
+    );
+
+    assert!(parse_dnssec_proof(&dnssec_proof[..dnssec_proof.len() - 1], &name).is_err());
+    assert!(parse_dnssec_proof(&dnssec_proof[..10], &name).is_err());
+    let other_name = bip353_name("notmatt@mattcorallo.com").unwrap();
+    assert!(parse_dnssec_proof(&dnssec_proof, &other_name).is_err());
+}
+
+// A dnssec_proof reply as CLN hands it to the onion_message_recv_secret hook
+// reaches the waiting query and verifies
+#[test]
+fn test_dnssec_proof_reply() {
+    use serde_json::json;
+
+    use crate::bip353::verify_bip353_proof;
+
+    let proof = Vec::<u8>::from_hex(include_str!("../tests/data/bip353_proof.hex").trim()).unwrap();
+    let name = bip353_name("matt@mattcorallo.com").unwrap();
+    let mut dnssec_proof = vec![name.as_str().len() as u8];
+    dnssec_proof.extend(name.as_str().as_bytes());
+    dnssec_proof.extend(u16::try_from(proof.len()).unwrap().to_be_bytes());
+    dnssec_proof.extend(&proof);
+
+    let path_id = [7; 32];
+    let onion_message = json!({
+        "pathsecret": path_id.to_lower_hex_string(),
+        "reply_blindedpath": {
+            "first_node_id": "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            "first_path_key": "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+            "hops": [{
+                "blinded_node_id": "02f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9",
+                "encrypted_recipient_data": "0011223344"
+            }]
+        },
+        "unknown_fields": [
+            {"number": 65537, "value": "00"},
+            {"number": TLV_DNSSEC_PROOF, "value": dnssec_proof.to_lower_hex_string()}
+        ]
+    });
+    let (reply_path_id, reply) = dnssec_proof_reply(&onion_message).unwrap();
+    assert_eq!(reply_path_id, path_id);
+    assert_eq!(reply, dnssec_proof);
+
+    let dnssec_queries = Mutex::new(HashMap::new());
+    let (sender, mut receiver) = oneshot::channel();
+    dnssec_queries.lock().insert(path_id, sender);
+    deliver_dnssec_proof(&dnssec_queries, [8; 32], reply.clone());
+    assert!(receiver.try_recv().is_err());
+    deliver_dnssec_proof(&dnssec_queries, reply_path_id, reply);
+    assert!(dnssec_queries.lock().is_empty());
+
+    let received = parse_dnssec_proof(&receiver.try_recv().unwrap(), &name).unwrap();
+    assert_eq!(received, proof);
+    let verified = verify_bip353_proof(&name, received, 1786000000).unwrap();
+    assert!(verified.offer.starts_with("lno1"));
+
+    let other_name = bip353_name("notmatt@mattcorallo.com").unwrap();
+    assert!(parse_dnssec_proof(&dnssec_proof, &other_name).is_err());
+    assert!(dnssec_proof_reply(&json!({"pathsecret": "00"})).is_none());
+    assert!(dnssec_proof_reply(&json!({"pathsecret": path_id.to_lower_hex_string()})).is_none());
+}
@@ -0,0 +1,71 @@
+use std::path::Path;
+
+use anyhow::{Context, Error, anyhow};
+use cln_plugin::Plugin;
+use cln_rpc::{
+    ClnRpc,
+    model::requests::{ConnectRequest, GetinfoRequest},
+};
+use serde_json::json;
+
+use crate::{
+    lnurl::{build_client, fetch_lnurl_json, lnurl_to_url, parse_callback_url},
+    structs::{LnurlTag, LnurlcConfig, PluginState},
+};
+
+pub async fn channel_lnurl(
+    plugin: Plugin<PluginState>,
+    lnurl: &str,
+    private: bool,
+) -> Result<serde_json::Value, Error> {
+    let (channel_url, tag) = lnurl_to_url(lnurl)?;
+    if let Some(t) = tag {
+        if t != LnurlTag::ChannelRequest {
+            return Err(anyhow!("LNURL is a {t} and not a channelRequest: {lnurl}"));
+        }
+    }
+
+    let config = plugin.state().config.lock().clone();
+    let client = build_client(&config)?;
+
+    let lnurlc_config: LnurlcConfig =
+        serde_json::from_value(fetch_lnurl_json(&client, &channel_url, "channel config").await?)
+            .context("Not a valid LNURL channel response")?;
+    log::debug!("lnurlc config: {lnurlc_config:?}");
+    if !lnurlc_config.tag.eq_ignore_ascii_case("channelRequest") {
+        return Err(anyhow!(
+            "LNURL config is not for a channelRequest: {}",
+            lnurlc_config.tag
+        ));
+    }
+
+    let mut rpc = ClnRpc::new(
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file),
+    )
+    .await?;
+
+    let connected = rpc
+        .call_typed(&ConnectRequest {
+            host: None,
+            port: None,
+            id: lnurlc_config.uri.clone(),
+        })
+        .await
+        .map_err(|e| anyhow!("LNURL: could not connect to {}: {e}", lnurlc_config.uri))?;
+    let our_id = rpc.call_typed(&GetinfoRequest {}).await?.id;
+
+    let mut callback_url = parse_callback_url(&lnurlc_config.callback)?;
+    callback_url
+        .query_pairs_mut()
+        .append_pair("k1", &lnurlc_config.k1)
+        .append_pair("remoteid", &our_id.to_string())
+        .append_pair("private", if private { "1" } else { "0" });
+
+    fetch_lnurl_json(&client, callback_url.as_str(), "channel callback").await?;
+
+    Ok(json!({
+        "peer_id": connected.id.to_string(),
+        "uri": lnurlc_config.uri,
+        "private": private,
+    }))
+}
@@ -0,0 +1,134 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+use cln_plugin::Plugin;
+use cln_rpc::{
+    ClnRpc,
+    model::requests::{DatastoreMode, DatastoreRequest, ListdatastoreRequest},
+    primitives::Sha256,
+};
+
+use crate::structs::{IdempotencyRecord, LnurlInvoiceRecord, PluginState};
+
+const DATASTORE_PREFIX: &str = "payany";
+const DATASTORE_INVOICES: &str = "invoices";
+const DATASTORE_IDEMPOTENCY: &str = "idempotency";
+
+fn invoice_key(payment_hash: &Sha256) -> Vec<String> {
+    vec![
+        DATASTORE_PREFIX.to_owned(),
+        DATASTORE_INVOICES.to_owned(),
+        payment_hash.to_string(),
+    ]
+}
+
+fn idempotency_key(key: &str) -> Vec<String> {
+    vec![
+        DATASTORE_PREFIX.to_owned(),
+        DATASTORE_IDEMPOTENCY.to_owned(),
+        key.to_owned(),
+    ]
+}
+
+pub async fn save_lnurl_record(
+    plugin: Plugin<PluginState>,
+    payment_hash: &Sha256,
+    record: &LnurlInvoiceRecord,
+    generation: Option<u64>,
+) -> Result<(), anyhow::Error> {
+    let mut rpc = ClnRpc::new(
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file),
+    )
+    .await?;
+
+    rpc.call_typed(&DatastoreRequest {
+        generation,
+        hex: None,
+        mode: Some(if generation.is_some() {
+            DatastoreMode::MUST_REPLACE
+        } else {
+            DatastoreMode::MUST_CREATE
+        }),
+        string: Some(serde_json::to_string(record)?),
+        key: invoice_key(payment_hash),
+    })
+    .await?;
+    Ok(())
+}
+
+pub async fn load_lnurl_record(
+    plugin: Plugin<PluginState>,
+    payment_hash: &Sha256,
+) -> Result<Option<(LnurlInvoiceRecord, u64)>, anyhow::Error> {
+    let mut rpc = ClnRpc::new(
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file),
+    )
+    .await?;
+
+    let datastore = rpc
+        .call_typed(&ListdatastoreRequest {
+            key: Some(invoice_key(payment_hash)),
+        })
+        .await?
+        .datastore;
+    let Some(entry) = datastore.into_iter().next() else {
+        return Ok(None);
+    };
+    let record_str = entry
+        .string
+        .ok_or_else(|| anyhow!("datastore entry for {payment_hash} is not a string"))?;
+    let record = serde_json::from_str(&record_str)?;
+    Ok(Some((record, entry.generation.unwrap_or(0))))
+}
+
+// Only the first call with a key may create its record, `replace` is for
+// updating the invoice of an already known key
+pub async fn save_idempotency_record(
+    plugin: Plugin<PluginState>,
+    key: &str,
+    record: &IdempotencyRecord,
+    replace: bool,
+) -> Result<(), anyhow::Error> {
+    let mut rpc = ClnRpc::new(
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file),
+    )
+    .await?;
+
+    rpc.call_typed(&DatastoreRequest {
+        generation: None,
+        hex: None,
+        mode: Some(if replace {
+            DatastoreMode::MUST_REPLACE
+        } else {
+            DatastoreMode::MUST_CREATE
+        }),
+        string: Some(serde_json::to_string(record)?),
+        key: idempotency_key(key),
+    })
+    .await?;
+    Ok(())
+}
+
+pub async fn load_idempotency_record(
+    plugin: Plugin<PluginState>,
+    key: &str,
+) -> Result<Option<IdempotencyRecord>, anyhow::Error> {
+    let mut rpc = ClnRpc::new(
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file),
+    )
+    .await?;
+
+    let datastore = rpc
+        .call_typed(&ListdatastoreRequest {
+            key: Some(idempotency_key(key)),
+        })
+        .await?
+        .datastore;
+    let Some(entry) = datastore.into_iter().next() else {
+        return Ok(None);
+    };
+    let record_str = entry
+        .string
+        .ok_or_else(|| anyhow!("datastore entry for idempotency_key {key} is not a string"))?;
+    Ok(Some(serde_json::from_str(&record_str)?))
+}
@@ -0,0 +1,272 @@
+use std::path::Path;
+
+use cln_plugin::Plugin;
+use cln_rpc::{ClnRpc, RpcError, primitives::Sha256};
+use serde_json::{Map, json};
+
+use crate::{
+    fetch::{bip353_address, fetch_offer_invoice, resolve_invstring_via},
+    refetch::{invstring_param, pay_refetching, payany_error, prepare_own_payment},
+    structs::{AddressMethod, Paycmd, PluginState, Resolution},
+    util::{EarlierPayment, earlier_payment_by_hash, invoice_payment_hash},
+};
+
+// Pay errors after which nothing is in flight and the other method of the
+// address may still succeed: PAY_DESTINATION_PERM_FAIL, PAY_ROUTE_NOT_FOUND,
+// PAY_ROUTE_TOO_EXPENSIVE, PAY_INVOICE_EXPIRED, PAY_UNSPECIFIED_ERROR,
+// PAY_STOPPED_RETRYING and the offer errors of fetching the invoice
+const FALLBACK_ERROR_CODES: [i32; 10] = [203, 205, 206, 207, 209, 210, 1002, 1003, 1004, 1005];
+
+// Pays a lightning address itself, so that a failed payment via one method can
+// be retried via the next method of `payany-address-priority`. Every attempt is
+// reported in the result or in the error's data. `params` are the resolved
+// params of the first method, offers are turned into an invoice before paying,
+// so its payment hash can be checked before falling back. Every invoice is
+// checked against the budget.
+#[allow(clippy::too_many_arguments)]
+pub async fn pay_with_fallback(
+    plugin: Plugin<PluginState>,
+    paycmd: Paycmd,
+    mut params: Map<String, serde_json::Value>,
+    original_params: Map<String, serde_json::Value>,
+    convert_to_xpay: bool,
+    address_method: AddressMethod,
+    mut resolution: Resolution,
+    idempotency_key: Option<String>,
+) -> serde_json::Value {
+    let config = plugin.state().config.lock().clone();
+    let mut method = address_method;
+    let mut tried = vec![method];
+    let mut tried_offers = Vec::new();
+    let mut attempts = Vec::new();
+    loop {
+        if let Some(bip353) = &resolution.bip353 {
+            tried_offers.push(bip353.offer.clone());
+        }
+        // Nothing was paid if no invoice could be fetched for the offer
+        let (paid, pay_result) = match fetch_offer_invoice(
+            plugin.clone(),
+            &mut params,
+            bip353_address(&resolution, &original_params),
+        )
+        .await
+        {
+            Ok(_) => {
+                if let Err(e) = prepare_own_payment(
+                    plugin.clone(),
+                    &mut params,
+                    paycmd,
+                    idempotency_key.as_deref(),
+                    convert_to_xpay,
+                )
+                .await
+                {
+                    return fallback_error(e, attempts);
+                }
+                let max_refetch = if method == AddressMethod::Lnurl {
+                    config.lnurl_refetch
+                } else {
+                    0
+                };
+                let pay_result = pay_refetching(
+                    plugin.clone(),
+                    paycmd,
+                    params.clone(),
+                    &original_params,
+                    convert_to_xpay,
+                    max_refetch,
+                    idempotency_key.as_deref(),
+                )
+                .await;
+                (true, pay_result)
+            }
+            Err(e) => (
+                false,
+                Err(payany_error(format!("payany could not fetch invoice: {e}"))),
+            ),
+        };
+        let invstring = invstring_param(&params).unwrap_or_default().to_owned();
+        let pay_error = match pay_result {
+            Ok(o) => {
+                attempts.push(json!({"method": method.to_string(), "invstring": invstring}));
+                return fallback_result(o, method, attempts);
+            }
+            Err(e) => e,
+        };
+        attempts.push(json!({
+            "method": method.to_string(),
+            "invstring": invstring,
+            "error": pay_error,
+        }));
+
+        if paid && !can_fall_back(plugin.clone(), &pay_error, &invstring).await {
+            return fallback_error(pay_error, attempts);
+        }
+
+        // bip353 and blip32 resolve the same DNS record, its offer is not paid twice
+        loop {
+            let remaining: Vec<AddressMethod> = config
+                .address_priority
+                .iter()
+                .filter(|m| !tried.contains(m))
+                .copied()
+                .collect();
+            if remaining.is_empty() {
+                return fallback_error(pay_error, attempts);
+            }
+            params = original_params.clone();
+            resolution = match resolve_invstring_via(plugin.clone(), &mut params, remaining).await {
+                Ok(o) => o,
+                Err(e) => {
+                    return fallback_error(
+                        payany_error(format!(
+                            "payany could not fall back after `{}`: {e}",
+                            pay_error.message
+                        )),
+                        attempts,
+                    );
+                }
+            };
+            let next_method = resolution.address_method.unwrap_or(method);
+            tried.push(next_method);
+            if resolution
+                .bip353
+                .as_ref()
+                .is_some_and(|b| tried_offers.contains(&b.offer))
+            {
+                log::info!("Not falling back to {next_method}, its offer was already tried");
+                continue;
+            }
+            log::info!(
+                "Paying via {method} failed: {}, trying {next_method} instead",
+                pay_error.message
+            );
+            method = next_method;
+            break;
+        }
+    }
+}
+
+// Falling back must never pay twice, so the failed invoice must not have any
+// pending or complete parts left
+async fn can_fall_back(plugin: Plugin<PluginState>, pay_error: &RpcError, invstring: &str) -> bool {
+    if !pay_error
+        .code
+        .is_some_and(|c| FALLBACK_ERROR_CODES.contains(&c))
+    {
+        return false;
+    }
+    let Ok(mut rpc) = ClnRpc::new(
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file),
+    )
+    .await
+    else {
+        return false;
+    };
+    let payment_hash = invoice_payment_hash(&mut rpc, invstring)
+        .await
+        .ok()
+        .flatten();
+    let earlier = match payment_hash {
+        Some(hash) => earlier_payment_by_hash(plugin, &mut rpc, hash).await,
+        None => Ok(None),
+    };
+    nothing_in_flight(payment_hash, earlier)
+}
+
+// Without a payment hash the payments of the invoice can't be looked up
+fn nothing_in_flight(
+    payment_hash: Option<Sha256>,
+    earlier: Result<Option<EarlierPayment>, anyhow::Error>,
+) -> bool {
+    if payment_hash.is_none() {
+        log::info!("Not falling back, the failed invoice has no payment hash to check");
+        return false;
+    }
+    match earlier {
+        Ok(None) => true,
+        Ok(Some((payment_hash, status, _payments))) => {
+            log::info!("Not falling back, payment of {payment_hash} is {status}");
+            false
+        }
+        Err(e) => {
+            log::info!("Not falling back, could not check for earlier payments: {e}");
+            false
+        }
+    }
+}
+
+fn fallback_result(
+    mut result: serde_json::Value,
+    method: AddressMethod,
+    attempts: Vec<serde_json::Value>,
+) -> serde_json::Value {
+    if let Some(result_obj) = result.as_object_mut() {
+        result_obj.insert("address_method".to_owned(), json!(method.to_string()));
+        result_obj.insert("attempts".to_owned(), json!(attempts));
+    }
+    json!({"return": {"result": result}})
+}
+
+fn fallback_error(pay_error: RpcError, attempts: Vec<serde_json::Value>) -> serde_json::Value {
+    json!({"return": {"error": json!(RpcError {
+        code: pay_error.code,
+        message: pay_error.message,
+        data: Some(json!({"attempts": attempts})),
+    })}})
+}
+
+#[test]
+fn test_fallback_attempts() {
+    let lnurl_attempt = json!({
+        "method": "lnurl",
+        "invstring": "lnbc1",
+        "error": {"code": 209, "message": "no route"},
+    });
+    let bip353_attempt = json!({"method": "bip353", "invstring": "lni1"});
+    let result = fallback_result(
+        json!({"payment_preimage": "00"}),
+        AddressMethod::Bip353,
+        vec![lnurl_attempt.clone(), bip353_attempt.clone()],
+    );
+    assert_eq!(result["return"]["result"]["address_method"], "bip353");
+    assert_eq!(
+        result["return"]["result"]["attempts"],
+        json!([lnurl_attempt, bip353_attempt])
+    );
+    assert_eq!(result["return"]["result"]["payment_preimage"], "00");
+
+    let error = fallback_error(
+        RpcError {
+            code: Some(209),
+            message: "no route".to_owned(),
+            data: None,
+        },
+        vec![lnurl_attempt.clone()],
+    );
+    assert_eq!(error["return"]["error"]["code"], 209);
+    assert_eq!(
+        error["return"]["error"]["data"]["attempts"],
+        json!([lnurl_attempt])
+    );
+}
+
+#[test]
+fn test_nothing_in_flight() {
+    let payment_hash = Sha256::const_hash(b"payment");
+    assert!(nothing_in_flight(Some(payment_hash), Ok(None)));
+    // Offers and undecodable invoices have no payment hash to check
+    assert!(!nothing_in_flight(None, Ok(None)));
+    assert!(!nothing_in_flight(
+        Some(payment_hash),
+        Ok(Some((payment_hash, "pending", Vec::new())))
+    ));
+    assert!(!nothing_in_flight(
+        Some(payment_hash),
+        Ok(Some((payment_hash, "complete", Vec::new())))
+    ));
+    assert!(!nothing_in_flight(
+        Some(payment_hash),
+        Err(anyhow::anyhow!("rpc down"))
+    ));
+}
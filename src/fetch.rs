@@ -1,17 +1,152 @@
+use std::{path::Path, time::Duration};
+
 use anyhow::{Error, anyhow};
+use bitcoin::hashes::{Hash, sha256};
+use chrono::Utc;
 use cln_plugin::Plugin;
-use cln_rpc::primitives::Amount;
-use serde_json::Map;
+use cln_rpc::{
+    ClnRpc,
+    model::{
+        requests::{DecodeRequest, FetchinvoiceRequest},
+        responses::DecodeType,
+    },
+    primitives::Amount,
+};
+use serde_json::{Map, json};
+use tokio::{sync::mpsc, time::Instant};
 
 use crate::{
-    lnurl::{process_lnurl_invoice, resolve_lnurl, try_fetch_lnurl},
-    structs::{PluginState, URI_SCHEMES},
+    bip353::resolve_bip353,
+    blip32::resolve_blip32,
+    invoice::validate_offer_invoice,
+    lnurl::{
+        decode_lnurl,
+        fetch_lnurlp_config,
+        fetch_lnurlp_invoice,
+        lnurl_error_data,
+        lnurl_fallback_param,
+        lnurlp_amount,
+        lud17_to_url,
+        process_lnurl_invoice,
+        resolve_lnurl,
+        service_url_scheme,
+        unpayable_tag_error,
+        url_query_tag,
+    },
+    metadata::metadata_to_json,
+    structs::{
+        AddressLookup,
+        AddressMethod,
+        AddressMethodFailure,
+        AddressResolutionError,
+        Config,
+        LnurlQuote,
+        LnurlTag,
+        LnurlpConfig,
+        LnurlpTarget,
+        PayerInfo,
+        PluginState,
+        Resolution,
+        URI_SCHEMES,
+        ZapInfo,
+    },
+    util::strip_prefix_ignore_ascii_case,
 };
 
-pub async fn resolve_invstring(
-    plugin: Plugin<PluginState>,
-    params: &mut Map<String, serde_json::Value>,
-) -> Result<(), Error> {
+// Seconds a quote of `payany` can be confirmed in
+const QUOTE_EXPIRY: u64 = 600;
+
+fn get_string_param(
+    params: &Map<String, serde_json::Value>,
+    name: &str,
+) -> Result<Option<String>, Error> {
+    if let Some(val) = params.get(name) {
+        Ok(Some(
+            val.as_str()
+                .ok_or_else(|| anyhow!("`{name}` must be a string"))?
+                .to_owned(),
+        ))
+    } else {
+        Ok(None)
+    }
+}
+
+fn get_zap_params(params: &Map<String, serde_json::Value>) -> Result<Option<ZapInfo>, Error> {
+    let Some(recipient) = get_string_param(params, "zap_pubkey")? else {
+        if ["zap_event", "zap_relays", "zap_content"]
+            .iter()
+            .any(|p| params.contains_key(*p))
+        {
+            return Err(anyhow!("zap: missing `zap_pubkey` of the recipient"));
+        }
+        return Ok(None);
+    };
+    let relays = match params.get("zap_relays") {
+        Some(serde_json::Value::Array(relays)) => relays
+            .iter()
+            .map(|r| {
+                r.as_str()
+                    .map(ToOwned::to_owned)
+                    .ok_or_else(|| anyhow!("`zap_relays` must be strings"))
+            })
+            .collect::<Result<Vec<String>, Error>>()?,
+        Some(serde_json::Value::String(relays)) => relays
+            .split(',')
+            .map(|r| r.trim().to_owned())
+            .filter(|r| !r.is_empty())
+            .collect(),
+        Some(_) => return Err(anyhow!("`zap_relays` must be an array of strings")),
+        None => Vec::new(),
+    };
+    if relays.is_empty() {
+        return Err(anyhow!(
+            "zap: missing `zap_relays` to publish the zap receipt to"
+        ));
+    }
+    Ok(Some(ZapInfo {
+        recipient,
+        event: get_string_param(params, "zap_event")?,
+        relays,
+        content: get_string_param(params, "zap_content")?,
+    }))
+}
+
+fn get_amount_msat(params: &Map<String, serde_json::Value>) -> Result<Option<Amount>, Error> {
+    if let Some(amt) = params.get("amount_msat") {
+        Ok(Some(Amount::from_msat(amt.as_u64().ok_or_else(|| {
+            anyhow!("`amount_msat` must be an integer")
+        })?)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn get_payer_info(
+    config: &Config,
+    params: &Map<String, serde_json::Value>,
+) -> Result<PayerInfo, Error> {
+    let message = if let Some(msg) = params.get("message") {
+        match msg {
+            serde_json::Value::Number(number) => Some(number.to_string()),
+            serde_json::Value::String(s) => Some(s.to_owned()),
+            _ => return Err(anyhow!("`message` must be a string")),
+        }
+    } else {
+        None
+    };
+    Ok(PayerInfo {
+        message,
+        name: get_string_param(params, "payer_name")?.or_else(|| config.payer_name.clone()),
+        identifier: get_string_param(params, "payer_identifier")?
+            .or_else(|| config.payer_identifier.clone()),
+        zap: get_zap_params(params)?,
+    })
+}
+
+// Returns the name of the invstring parameter and its value without URI scheme
+pub fn get_invstring(
+    params: &Map<String, serde_json::Value>,
+) -> Result<(&'static str, String), Error> {
     let invstring_name = if params.get("invstring").is_some() {
         "invstring"
     } else if params.get("bolt11").is_some() {
@@ -19,123 +154,532 @@ pub async fn resolve_invstring(
     } else {
         return Err(anyhow!("missing required parameter: `invstring`/`bolt11`"));
     };
-    let invstring_lower_presplit = if let Some(invstr) = params.get(invstring_name) {
+    let invstring_presplit = if let Some(invstr) = params.get(invstring_name) {
         invstr
             .as_str()
             .ok_or_else(|| anyhow!("{invstring_name} must be a string: {invstr}"))?
             .to_owned()
-            .to_lowercase()
     } else {
         return Err(anyhow!("missing required parameter: {invstring_name}"));
     };
-    let mut invstring_lower = invstring_lower_presplit.as_str();
+    let mut invstring = invstring_presplit.as_str();
     for uri_scheme in URI_SCHEMES {
-        if let Some(stripped) = invstring_lower.strip_prefix(uri_scheme) {
-            invstring_lower = stripped;
+        if let Some(stripped) = strip_prefix_ignore_ascii_case(invstring, uri_scheme) {
+            invstring = stripped;
             break;
         }
     }
-    let amount_msat = if let Some(amt) = params.get("amount_msat") {
-        Some(Amount::from_msat(amt.as_u64().ok_or_else(|| {
-            anyhow!("`amount_msat` must be an integer")
-        })?))
+    if let Some(fallback) = lnurl_fallback_param(invstring) {
+        log::debug!("lud-01 fallback url detected");
+        return Ok((invstring_name, fallback));
+    }
+    Ok((invstring_name, invstring.to_owned()))
+}
+
+// Finds the LNURL-pay config url of LUD-17 urls, bech32 LNURLs and lightning addresses
+fn lnurlp_target(invstring: &str) -> Result<Option<LnurlpTarget>, Error> {
+    let invstring_lower = invstring.to_lowercase();
+    if let Some((lnurl_url, tag)) = lud17_to_url(invstring)? {
+        log::debug!("lud-17 {tag} url detected: {}", tag.protocol());
+        if tag != LnurlTag::PayRequest {
+            return Err(unpayable_tag_error(tag, invstring));
+        }
+        Ok(Some(LnurlpTarget::Lnurl(lnurl_url)))
+    } else if invstring_lower.starts_with("lnurl") {
+        log::debug!("lnurl detected");
+        let lnurl_url = decode_lnurl(&invstring_lower)?;
+        if let Some(tag) = url_query_tag(&lnurl_url) {
+            log::debug!("lnurl {tag} detected: {}", tag.protocol());
+            if tag != LnurlTag::PayRequest {
+                return Err(unpayable_tag_error(tag, invstring));
+            }
+        }
+        Ok(Some(LnurlpTarget::Lnurl(lnurl_url)))
+    } else if invstring_lower.contains('@') {
+        log::debug!("lnaddress detected");
+        Ok(Some(LnurlpTarget::Lnaddress(invstring_lower)))
     } else {
-        None
+        Ok(None)
+    }
+}
+
+pub async fn resolve_invstring(
+    plugin: Plugin<PluginState>,
+    params: &mut Map<String, serde_json::Value>,
+) -> Result<Resolution, Error> {
+    let address_priority = plugin.state().config.lock().address_priority.clone();
+    resolve_invstring_via(plugin, params, address_priority).await
+}
+
+// Like `resolve_invstring` but lightning addresses are only resolved with the
+// given methods, e.g. to fetch a new invoice from the same service
+pub async fn resolve_invstring_via(
+    plugin: Plugin<PluginState>,
+    params: &mut Map<String, serde_json::Value>,
+    address_methods: Vec<AddressMethod>,
+) -> Result<Resolution, Error> {
+    let (invstring_name, invstring) = get_invstring(params)?;
+    let amount_msat = get_amount_msat(params)?;
+    let config = plugin.state().config.lock().clone();
+    let payer = get_payer_info(&config, params)?;
+
+    match lnurlp_target(&invstring)? {
+        Some(LnurlpTarget::Lnurl(lnurl_url)) => {
+            resolve_lnurl(
+                plugin,
+                invstring_name,
+                lnurl_url,
+                None,
+                amount_msat,
+                payer,
+                params,
+            )
+            .await
+        }
+        Some(LnurlpTarget::Lnaddress(lnaddress)) => {
+            // Zaps are a LNURL feature, bip353 offers can't carry them
+            let address_methods = if payer.zap.is_some() {
+                if !address_methods.contains(&AddressMethod::Lnurl) {
+                    return Err(anyhow!(
+                        "zap: lightning addresses can only be zapped via lnurl"
+                    ));
+                }
+                vec![AddressMethod::Lnurl]
+            } else {
+                address_methods
+            };
+            resolve_lnaddress(
+                plugin,
+                invstring_name,
+                &lnaddress,
+                amount_msat,
+                payer,
+                params,
+                address_methods,
+            )
+            .await
+        }
+        None if payer.zap.is_some() => Err(anyhow!(
+            "zap: only LNURLs and lightning addresses can be zapped"
+        )),
+        None if invstring.to_lowercase().starts_with("lno") => {
+            log::debug!("regular bolt12 offer forwarded");
+            Ok(Resolution::default())
+        }
+        None => {
+            log::debug!("regular invoice forwarded");
+            Ok(Resolution::default())
+        }
+    }
+}
+
+// Fetches only the LNURL-pay config, so limits and metadata can be shown before
+// committing to an amount. The returned quote_id is used to fetch the invoice later.
+pub async fn quote_invstring(
+    plugin: Plugin<PluginState>,
+    params: &Map<String, serde_json::Value>,
+) -> Result<serde_json::Value, Error> {
+    let (_invstring_name, invstring) = get_invstring(params)?;
+    let (config_url, lnaddress) = match lnurlp_target(&invstring)? {
+        Some(LnurlpTarget::Lnurl(lnurl_url)) => (lnurl_url, None),
+        Some(LnurlpTarget::Lnaddress(lnaddress)) => {
+            (lnaddress_to_url(&lnaddress)?, Some(lnaddress))
+        }
+        None => {
+            return Err(anyhow!(
+                "quote: only LNURLs and lightning addresses can be quoted"
+            ));
+        }
     };
-    let message = if let Some(msg) = params.get("message") {
-        match msg {
-            serde_json::Value::Number(number) => Some(number.to_string()),
-            serde_json::Value::String(s) => Some(s.to_owned()),
-            _ => return Err(anyhow!("`message` must be a string")),
+    let config = plugin.state().config.lock().clone();
+    let lnurlp_config = fetch_lnurlp_config(&config, &config_url).await?;
+
+    let now = Utc::now();
+    let now_secs = u64::try_from(now.timestamp())?;
+    let expires_at = now_secs + QUOTE_EXPIRY;
+    let quote_id = sha256::Hash::hash(
+        format!(
+            "{config_url}{}",
+            now.timestamp_nanos_opt().unwrap_or_default()
+        )
+        .as_bytes(),
+    )
+    .to_string();
+
+    let mut result = json!({
+        "quote_id": quote_id,
+        "expires_at": expires_at,
+        "min_sendable_msat": lnurlp_config.min_sendable,
+        "max_sendable_msat": lnurlp_config.max_sendable,
+        "metadata": metadata_to_json(&lnurlp_config.parsed_metadata),
+    });
+    let result_obj = result.as_object_mut().unwrap();
+    if let Some(comment_allowed) = lnurlp_config.comment_allowed {
+        result_obj.insert("comment_allowed".to_owned(), json!(comment_allowed));
+    }
+    if lnurlp_config.allows_nostr == Some(true) {
+        result_obj.insert("allows_nostr".to_owned(), json!(true));
+    }
+
+    let mut quotes = plugin.state().quotes.lock();
+    quotes.retain(|_, q| q.expires_at > now_secs);
+    quotes.insert(
+        quote_id,
+        LnurlQuote {
+            invstring,
+            config_url,
+            lnaddress,
+            lnurlp_config,
+            expires_at,
+        },
+    );
+    Ok(result)
+}
+
+pub async fn resolve_quote(
+    plugin: Plugin<PluginState>,
+    params: &mut Map<String, serde_json::Value>,
+) -> Result<Resolution, Error> {
+    let quote_id = get_string_param(params, "quote_id")?
+        .ok_or_else(|| anyhow!("missing required parameter: `quote_id`"))?;
+    let now = u64::try_from(Utc::now().timestamp())?;
+    let quote = {
+        let mut quotes = plugin.state().quotes.lock();
+        quotes.retain(|_, q| q.expires_at > now);
+        quotes.get(&quote_id).cloned()
+    }
+    .ok_or_else(|| anyhow!("quote: unknown or expired quote_id: {quote_id}"))?;
+    params.insert("invstring".to_owned(), json!(quote.invstring));
+
+    let config = plugin.state().config.lock().clone();
+    let payer = get_payer_info(&config, params)?;
+    let amount_msat = lnurlp_amount(&quote.lnurlp_config, get_amount_msat(params)?)?;
+
+    let (lnurlp_callback, committed_description) = fetch_lnurlp_invoice(
+        plugin.clone(),
+        &config,
+        &quote.lnurlp_config,
+        quote.lnaddress.as_deref(),
+        &quote.config_url,
+        amount_msat,
+        payer,
+    )
+    .await?;
+
+    process_lnurl_invoice(
+        plugin,
+        "invstring",
+        lnurlp_callback,
+        quote.lnurlp_config,
+        committed_description,
+        amount_msat,
+        &config,
+        params,
+    )
+    .await
+}
+
+// The payany RPC hands out invoices, so offers, also those of bip353 addresses,
+// are turned into a checked bolt12 invoice like pay/xpay would do. Returns the
+// offer if an invoice was fetched for it.
+pub async fn fetch_offer_invoice(
+    plugin: Plugin<PluginState>,
+    params: &mut Map<String, serde_json::Value>,
+    bip353: Option<String>,
+) -> Result<Option<String>, Error> {
+    let (invstring_name, offer) = get_invstring(params)?;
+    if !offer.to_lowercase().starts_with("lno") {
+        return Ok(None);
+    }
+    let amount_msat = get_amount_msat(params)?;
+    let config = plugin.state().config.lock().clone();
+    let payer_note = get_payer_info(&config, params)?.message;
+
+    let mut rpc = ClnRpc::new(
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file),
+    )
+    .await?;
+    let offer_decoded = rpc
+        .call_typed(&DecodeRequest {
+            string: offer.clone(),
+        })
+        .await
+        .map_err(|e| anyhow!("could not decode offer: {}", e.message))?;
+    if offer_decoded.item_type != DecodeType::BOLT12_OFFER || !offer_decoded.valid {
+        return Err(anyhow!("not a valid bolt12 offer: {offer}"));
+    }
+    let offer_id = offer_decoded
+        .offer_id
+        .ok_or_else(|| anyhow!("offer is missing its offer_id"))?;
+    // Offers with an amount must not get one in the invoice_request
+    let request_amount_msat = if offer_decoded.offer_amount.is_some() {
+        if let (Some(offer_amount), Some(amount)) = (offer_decoded.offer_amount_msat, amount_msat) {
+            if offer_amount != amount {
+                return Err(anyhow!(
+                    "offer is for {}msat and not for {}msat",
+                    offer_amount.msat(),
+                    amount.msat()
+                ));
+            }
         }
-    } else {
         None
+    } else {
+        Some(amount_msat.ok_or_else(|| anyhow!("missing `amount_msat` for offer without amount"))?)
     };
 
-    if invstring_lower.starts_with("lnurl") {
-        log::debug!("lnurl detected");
-        if amount_msat.is_none() {
-            return Err(anyhow!("lnurl: missing amount_msat"));
-        }
-        return resolve_lnurl(
-            plugin,
-            invstring_name,
-            invstring_lower,
-            None,
-            amount_msat.unwrap(),
-            message,
-            params,
-        )
-        .await;
-    } else if invstring_lower.contains('@') {
-        log::debug!("lnaddress detected");
-        if amount_msat.is_none() {
-            return Err(anyhow!("lnaddress: missing amount_msat"));
-        }
-        return resolve_lnaddress(
-            plugin,
-            invstring_name,
-            invstring_lower,
-            amount_msat.unwrap(),
-            message,
-            params,
-        )
-        .await;
-    } else if invstring_lower.starts_with("lno") {
-        log::debug!("regular bolt12 offer forwarded");
-        return Ok(());
+    let invoice = rpc
+        .call_typed(&FetchinvoiceRequest {
+            offer: offer.clone(),
+            amount_msat: request_amount_msat,
+            payer_note: payer_note.clone(),
+            bip353,
+            payer_metadata: None,
+            quantity: None,
+            recurrence_counter: None,
+            recurrence_label: None,
+            recurrence_start: None,
+            timeout: None,
+        })
+        .await
+        .map_err(|e| anyhow!("could not fetch invoice for offer: {}", e.message))?
+        .invoice;
+    let invoice_decoded = rpc
+        .call_typed(&DecodeRequest {
+            string: invoice.clone(),
+        })
+        .await?;
+    validate_offer_invoice(
+        &invoice_decoded,
+        &offer_id,
+        offer_decoded.offer_amount_msat.or(request_amount_msat),
+        payer_note.as_deref(),
+        &config,
+        Utc::now().timestamp().try_into()?,
+    )?;
+
+    params.insert(invstring_name.to_owned(), json!(invoice));
+    // The invoice carries the amount now
+    params.remove("amount_msat");
+    Ok(Some(offer))
+}
+
+// fetchinvoice puts the bip353 address into the invoice_request for the payee
+pub fn bip353_address(
+    resolution: &Resolution,
+    original_params: &Map<String, serde_json::Value>,
+) -> Option<String> {
+    if resolution.bip353.is_some() {
+        get_invstring(original_params)
+            .ok()
+            .map(|(_, address)| address)
+    } else {
+        None
     }
-    log::debug!("regular invoice forwarded");
-    Ok(())
 }
 
+// All address methods are looked up at the same time, so a dead endpoint of one
+// method doesn't delay the others for long. The first method of the priority
+// order that resolved the address is used. If a lower priority method resolved
+// while a higher one is still pending, the higher one gets
+// `payany-address-method-grace` seconds before the lower one is used. The remaining
+// lookups are cancelled and the params are only changed by the chosen method.
 async fn resolve_lnaddress(
     plugin: Plugin<PluginState>,
-    invstring_name: &str,
+    invstring_name: &'static str,
     lnaddress: &str,
-    amount_msat: Amount,
-    message: Option<String>,
+    amount_msat: Option<Amount>,
+    payer: PayerInfo,
     params: &mut Map<String, serde_json::Value>,
-) -> Result<(), Error> {
-    let address_parts = lnaddress.split('@').collect::<Vec<&str>>();
+    address_methods: Vec<AddressMethod>,
+) -> Result<Resolution, Error> {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut tasks = Vec::new();
+    for (index, method) in address_methods.iter().copied().enumerate() {
+        let plugin = plugin.clone();
+        let lnaddress = lnaddress.to_owned();
+        let mut method_params = params.clone();
+        let sender = sender.clone();
+        tasks.push(tokio::spawn(async move {
+            let lookup = match method {
+                AddressMethod::Lnurl => {
+                    let config = plugin.state().config.lock().clone();
+                    fetch_lnaddress_config(&config, &lnaddress)
+                        .await
+                        .map(|c| AddressLookup::Lnurl(Box::new(c)))
+                }
+                AddressMethod::Bip353 => resolve_bip353(
+                    plugin,
+                    invstring_name,
+                    &lnaddress,
+                    amount_msat,
+                    &mut method_params,
+                )
+                .await
+                .map(|r| AddressLookup::Offer(Box::new(r), method_params)),
+                AddressMethod::Blip32 => resolve_blip32(
+                    plugin,
+                    invstring_name,
+                    &lnaddress,
+                    amount_msat,
+                    &mut method_params,
+                )
+                .await
+                .map(|r| AddressLookup::Offer(Box::new(r), method_params)),
+            };
+            if let Err(e) = &lookup {
+                log::warn!("Could not resolve {lnaddress} via {method}: {e}");
+            }
+            let _ = sender.send((index, lookup));
+        }));
+    }
+    drop(sender);
 
-    if address_parts.len() != 2 {
-        return Err(anyhow!("LN-address invalid: {lnaddress}"));
+    let mut lookups: Vec<Option<Result<AddressLookup, Error>>> =
+        address_methods.iter().map(|_| None).collect();
+    let grace = Duration::from_secs(plugin.state().config.lock().address_method_grace);
+    let mut grace_deadline = None;
+    while let Some(index) =
+        next_address_lookup(&mut lookups, &mut receiver, &mut grace_deadline, grace).await
+    {
+        let method = address_methods[index];
+        let Some(Ok(lookup)) = lookups[index].take() else {
+            unreachable!("chosen lookup resolved");
+        };
+        let resolved = match lookup {
+            AddressLookup::Lnurl(lnurlp_config) => {
+                let mut method_params = params.clone();
+                resolve_lnaddress_lnurl(
+                    plugin.clone(),
+                    invstring_name,
+                    lnaddress,
+                    *lnurlp_config,
+                    amount_msat,
+                    payer.clone(),
+                    &mut method_params,
+                )
+                .await
+                .map(|r| (r, method_params))
+            }
+            AddressLookup::Offer(resolution, method_params) => Ok((*resolution, method_params)),
+        };
+        match resolved {
+            Ok((mut resolution, method_params)) => {
+                log::info!("Resolved {lnaddress} via {method}");
+                for (i, task) in tasks.iter().enumerate() {
+                    if lookups[i].is_none() && i != index {
+                        log::debug!(
+                            "Cancelling {} resolution of {lnaddress}",
+                            address_methods[i]
+                        );
+                    }
+                    task.abort();
+                }
+                resolution.address_method = Some(method);
+                *params = method_params;
+                return Ok(resolution);
+            }
+            Err(e) => {
+                log::warn!("Could not resolve {lnaddress} via {method}: {e}");
+                lookups[index] = Some(Err(e));
+            }
+        }
     }
 
-    let user = address_parts.first().unwrap();
+    let failures = address_methods
+        .into_iter()
+        .zip(lookups)
+        .filter_map(|(method, lookup)| match lookup {
+            Some(Err(e)) => Some(AddressMethodFailure {
+                method,
+                message: e.to_string(),
+                data: lnurl_error_data(&e),
+            }),
+            _ => None,
+        })
+        .collect();
+    Err(anyhow!(AddressResolutionError {
+        address: lnaddress.to_owned(),
+        failures,
+    }))
+}
 
-    let domain = address_parts.get(1).unwrap();
+// Returns the index of the lookup to use: the first of the priority order that
+// resolved, or after the grace period a lower priority one while the preferred
+// lookup is still pending. None once every lookup failed.
+async fn next_address_lookup<T>(
+    lookups: &mut [Option<Result<T, Error>>],
+    receiver: &mut mpsc::UnboundedReceiver<(usize, Result<T, Error>)>,
+    grace_deadline: &mut Option<Instant>,
+    grace: Duration,
+) -> Option<usize> {
+    loop {
+        let preferred = lookups.iter().position(|l| !matches!(l, Some(Err(_))))?;
+        if matches!(lookups[preferred], Some(Ok(_))) {
+            return Some(preferred);
+        }
+        if let Some(resolved) = lookups.iter().position(|l| matches!(l, Some(Ok(_)))) {
+            let deadline = *grace_deadline.get_or_insert_with(|| Instant::now() + grace);
+            if Instant::now() >= deadline {
+                return Some(resolved);
+            }
+        }
 
-    let ln_service_url = if domain.contains("localhost") || domain.contains("127.0.0.1") {
-        format!("http://{domain}/.well-known/lnurlp/{user}")
-    } else {
-        format!("https://{domain}/.well-known/lnurlp/{user}")
-    };
+        let received = match *grace_deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(r) => r,
+                Err(_elapsed) => continue,
+            },
+            None => receiver.recv().await,
+        };
+        if let Some((index, lookup)) = received {
+            lookups[index] = Some(lookup);
+        } else {
+            for lookup in lookups.iter_mut().filter(|l| l.is_none()) {
+                *lookup = Some(Err(anyhow!("resolution task failed")));
+            }
+        }
+    }
+}
+
+// The part of resolving a lightning address via LNURL that runs concurrently
+// with the other methods, it has no side effects
+async fn fetch_lnaddress_config(config: &Config, lnaddress: &str) -> Result<LnurlpConfig, Error> {
+    let ln_service_url = lnaddress_to_url(lnaddress)?;
+    fetch_lnurlp_config(config, &ln_service_url).await
+}
+
+async fn resolve_lnaddress_lnurl(
+    plugin: Plugin<PluginState>,
+    invstring_name: &str,
+    lnaddress: &str,
+    lnurlp_config: LnurlpConfig,
+    amount_msat: Option<Amount>,
+    payer: PayerInfo,
+    params: &mut Map<String, serde_json::Value>,
+) -> Result<Resolution, Error> {
+    let ln_service_url = lnaddress_to_url(lnaddress)?;
 
     let config = plugin.state().config.lock().clone();
 
-    let (lnurlp_callback, lnurlp_config) = match try_fetch_lnurl(
+    let amount_msat = lnurlp_amount(&lnurlp_config, amount_msat)?;
+
+    let (lnurlp_callback, committed_description) = fetch_lnurlp_invoice(
+        plugin.clone(),
         &config,
+        &lnurlp_config,
         Some(lnaddress),
-        ln_service_url,
+        &ln_service_url,
         amount_msat,
-        message,
+        payer,
     )
-    .await
-    {
-        Ok((cb, cf)) => (cb, cf),
-        Err(e) => {
-            log::info!("Error fetching lnurlp config: {e}, trying bip353 instead...");
-            return Ok(());
-        }
-    };
+    .await?;
 
     match process_lnurl_invoice(
         plugin,
         invstring_name,
         lnurlp_callback,
         lnurlp_config,
+        committed_description,
         amount_msat,
         &config,
         params,
@@ -146,3 +690,86 @@ async fn resolve_lnaddress(
         Err(lnurl_error) => Err(anyhow!("Error fetching invoice from lnurl: {lnurl_error}")),
     }
 }
+
+// LUD-16: user@domain is served at https://domain/.well-known/lnurlp/user
+fn lnaddress_to_url(lnaddress: &str) -> Result<String, Error> {
+    let address_parts = lnaddress.split('@').collect::<Vec<&str>>();
+
+    if address_parts.len() != 2 {
+        return Err(anyhow!("LN-address invalid: {lnaddress}"));
+    }
+
+    let user = address_parts.first().unwrap();
+
+    let domain = address_parts.get(1).unwrap();
+
+    Ok(format!(
+        "{}://{domain}/.well-known/lnurlp/{user}",
+        service_url_scheme(domain)
+    ))
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_next_address_lookup() {
+    let grace = Duration::from_secs(3);
+
+    // A hanging LNURL endpoint only delays a resolved bip353 address by the grace period
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut lookups: Vec<Option<Result<&str, Error>>> = vec![None, None];
+    let mut grace_deadline = None;
+    sender.send((1, Ok("bip353"))).unwrap();
+    let start = Instant::now();
+    assert_eq!(
+        next_address_lookup(&mut lookups, &mut receiver, &mut grace_deadline, grace).await,
+        Some(1)
+    );
+    assert_eq!(start.elapsed(), grace);
+    assert!(lookups[0].is_none());
+
+    // The preferred method still wins within the grace period
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut lookups: Vec<Option<Result<&str, Error>>> = vec![None, None];
+    let mut grace_deadline = None;
+    sender.send((1, Ok("bip353"))).unwrap();
+    tokio::spawn(async move {
+        tokio::time::sleep(grace / 2).await;
+        sender.send((0, Ok("lnurl"))).unwrap();
+    });
+    assert_eq!(
+        next_address_lookup(&mut lookups, &mut receiver, &mut grace_deadline, grace).await,
+        Some(0)
+    );
+
+    // Without waiting once the preferred method failed
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut lookups: Vec<Option<Result<&str, Error>>> = vec![None, None, None];
+    let mut grace_deadline = None;
+    sender.send((0, Err(anyhow!("lnurl down")))).unwrap();
+    sender.send((1, Ok("bip353"))).unwrap();
+    let start = Instant::now();
+    assert_eq!(
+        next_address_lookup(&mut lookups, &mut receiver, &mut grace_deadline, grace).await,
+        Some(1)
+    );
+    assert_eq!(start.elapsed(), Duration::ZERO);
+
+    // A method that failed after it was chosen gives way to the next one
+    lookups[1] = Some(Err(anyhow!("invoice failed")));
+    sender.send((2, Ok("blip32"))).unwrap();
+    assert_eq!(
+        next_address_lookup(&mut lookups, &mut receiver, &mut grace_deadline, grace).await,
+        Some(2)
+    );
+
+    // Every method failed, also those whose task died
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut lookups: Vec<Option<Result<&str, Error>>> = vec![None, None];
+    let mut grace_deadline = None;
+    sender.send((0, Err(anyhow!("lnurl down")))).unwrap();
+    drop(sender);
+    assert_eq!(
+        next_address_lookup(&mut lookups, &mut receiver, &mut grace_deadline, grace).await,
+        None
+    );
+    assert!(matches!(&lookups[1], Some(Err(e)) if e.to_string() == "resolution task failed"));
+}
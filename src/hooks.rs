@@ -1,13 +1,28 @@
+use std::path::Path;
+
 use anyhow::{Error, anyhow};
 use cln_plugin::Plugin;
-use cln_rpc::RpcError;
-use serde_json::json;
+use cln_rpc::{
+    ClnRpc,
+    RpcError,
+    primitives::{Secret, Sha256},
+};
+use serde_json::{Map, json};
 
 use crate::{
+    NOTIFICATION_SUCCESS_ACTION,
+    blip32::{deliver_dnssec_proof, dnssec_proof_reply},
     budget::budget_check,
-    fetch::resolve_invstring,
+    datastore::{load_lnurl_record, save_lnurl_record},
+    fallback::pay_with_fallback,
+    fetch::{bip353_address, fetch_offer_invoice, resolve_invstring},
+    idempotency::{check_idempotency_key, idempotency_key_param, save_idempotent_invoice},
+    lnurl::lnurl_error_data,
     parse::convert_pay_to_xpay,
-    structs::{ParamValue, Paycmd, PluginState, RpcCommand},
+    refetch::{invstring_param, pay_with_refetch},
+    structs::{IdempotentCall, PAYANY_PARAMS, ParamValue, Paycmd, PluginState, RpcCommand},
+    successaction::success_action_to_json,
+    util::earlier_payment,
 };
 
 pub async fn hook_handler(
@@ -61,10 +76,83 @@ pub async fn hook_handler(
     };
     log::debug!("params_obj: {params_as_object:?}");
 
-    match resolve_invstring(plugin.clone(), &mut params_as_object).await {
+    if invstring_param(&params_as_object)
+        .is_some_and(|i| plugin.state().own_invoices.lock().contains(i))
+    {
+        log::debug!("payment started by payany, continuing");
+        return Ok(json!({"result":"continue"}));
+    }
+    let original_params = params_as_object.clone();
+    let original_paycmd = paycmd;
+
+    let idempotency_key = match idempotency_key_param(&params_as_object) {
+        Ok(o) => o,
+        Err(e) => {
+            return Ok(json!({"return":{"error":json!(RpcError {
+                code: Some(-32602),
+                message: e.to_string(),
+                data: None,
+            })}}));
+        }
+    };
+    let mut idempotent_retry = false;
+    if let Some(key) = &idempotency_key {
+        match check_idempotency_key(plugin.clone(), key).await {
+            Ok(IdempotentCall::New) => (),
+            Ok(IdempotentCall::Complete(result)) => {
+                return Ok(json!({"return":{"result":result}}));
+            }
+            Ok(IdempotentCall::Pending(result)) => {
+                return Ok(json!({"return":{"error":json!(RpcError {
+                    code: Some(-32602),
+                    message: format!("payany: payment for idempotency_key {key} is still pending"),
+                    data: Some(result),
+                })}}));
+            }
+            Ok(IdempotentCall::Retry(record, _result)) => {
+                // Pay the invoice fetched by the first call again instead of a new one,
+                // its LNURL and zap params were already sent with the first call
+                params_as_object.retain(|param, _| !PAYANY_PARAMS.contains(&param.as_str()));
+                let invstring_name = if params_as_object.contains_key("bolt11") {
+                    "bolt11"
+                } else {
+                    "invstring"
+                };
+                if invstring_param(&params_as_object) != Some(record.invoice.as_str()) {
+                    params_as_object.remove("amount_msat");
+                }
+                params_as_object.insert(invstring_name.to_owned(), json!(record.invoice));
+                idempotent_retry = true;
+            }
+            Err(e) => {
+                return Ok(json!({"return":{"error":json!(RpcError {
+                    code: Some(-32602),
+                    message: format!("payany could not check idempotency_key: {e}"),
+                    data: None,
+                })}}));
+            }
+        }
+    }
+
+    let resolution = match resolve_invstring(plugin.clone(), &mut params_as_object).await {
         Ok(o) => o,
         Err(e) => {
-            params_as_object.remove("message");
+            return Ok(json!({"return": {"error":json!(RpcError {
+                code: Some(-32602),
+                message: format!("payany could not fetch invoice: {e}"),
+                data: lnurl_error_data(&e),
+            })}}));
+        }
+    };
+    // Offers have no payment hash, so the invoice is fetched here to be stored
+    if idempotency_key.is_some() && !idempotent_retry {
+        if let Err(e) = fetch_offer_invoice(
+            plugin.clone(),
+            &mut params_as_object,
+            bip353_address(&resolution, &original_params),
+        )
+        .await
+        {
             return Ok(json!({"return": {"error":json!(RpcError {
                 code: Some(-32602),
                 message: format!("payany could not fetch invoice: {e}"),
@@ -72,7 +160,13 @@ pub async fn hook_handler(
             })}}));
         }
     }
-    params_as_object.remove("message");
+    // Falling back prepares the params of every method it pays itself
+    let resolved_params = params_as_object.clone();
+    params_as_object.retain(|param, _| !PAYANY_PARAMS.contains(&param.as_str()));
+
+    if let Err(e) = check_earlier_payment(plugin.clone(), &params_as_object).await {
+        return Ok(json!({"return": {"error":json!(e)}}));
+    }
 
     if let Err(e) = budget_check(plugin.clone(), &params_as_object, paycmd).await {
         return Ok(json!({"return": {"error":json!(RpcError {
@@ -82,6 +176,19 @@ pub async fn hook_handler(
         })}}));
     }
 
+    if let Some(key) = &idempotency_key {
+        if !idempotent_retry {
+            let invstring = invstring_param(&params_as_object).unwrap_or_default();
+            if let Err(e) = save_idempotent_invoice(plugin.clone(), key, invstring, false).await {
+                return Ok(json!({"return": {"error":json!(RpcError {
+                    code: Some(-32602),
+                    message: format!("payany: {e}"),
+                    data: None,
+                })}}));
+            }
+        }
+    }
+
     if config.xpay_handle_pay && paycmd == Paycmd::Pay {
         if let Err(e) = convert_pay_to_xpay(plugin.clone(), &mut params_as_object).await {
             return Ok(json!({"return": {"error":json!(RpcError {
@@ -93,18 +200,151 @@ pub async fn hook_handler(
         paycmd = Paycmd::Xpay;
     }
 
+    if config.address_fallback {
+        if let Some(address_method) = resolution.address_method {
+            return Ok(pay_with_fallback(
+                plugin,
+                paycmd,
+                resolved_params,
+                original_params,
+                paycmd != original_paycmd,
+                address_method,
+                resolution,
+                idempotency_key,
+            )
+            .await);
+        }
+    }
+
+    if config.lnurl_refetch > 0 && resolution.from_lnurl {
+        return Ok(pay_with_refetch(
+            plugin,
+            paycmd,
+            params_as_object,
+            original_params,
+            paycmd != original_paycmd,
+            config.lnurl_refetch,
+            idempotency_key,
+            resolution.address_method,
+        )
+        .await);
+    }
+
     let result = json!({"replace": {"jsonrpc":"2.0",
     "id": root.rpc_command.id,
-    "method":format!("{}",match paycmd{
-        Paycmd::Pay => "pay",
-        Paycmd::Xpay => "xpay",
-        Paycmd::Renepay=> "renepay"
-    }),
+    "method":paycmd.method(),
     "params":params_as_object}});
     log::debug!("{result}");
     Ok(result)
 }
 
+pub async fn check_earlier_payment(
+    plugin: Plugin<PluginState>,
+    params: &Map<String, serde_json::Value>,
+) -> Result<(), RpcError> {
+    let Some(invstring) = invstring_param(params) else {
+        return Ok(());
+    };
+    let earlier = match ClnRpc::new(
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file),
+    )
+    .await
+    {
+        Ok(mut rpc) => earlier_payment(plugin, &mut rpc, invstring).await,
+        Err(e) => Err(anyhow!(e)),
+    };
+    let (payment_hash, status, payments) = match earlier {
+        Ok(Some(o)) => o,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            return Err(RpcError {
+                code: Some(-32602),
+                message: format!("payany could not check for earlier payments: {e}"),
+                data: None,
+            });
+        }
+    };
+    let message = if status == "complete" {
+        format!("payany: invoice {payment_hash} was already paid")
+    } else {
+        format!("payany: invoice {payment_hash} is already being paid")
+    };
+    log::info!("{message}");
+    Err(RpcError {
+        code: Some(-32602),
+        message,
+        data: Some(json!({
+            "payment_hash": payment_hash.to_string(),
+            "status": status,
+            "payments": payments,
+        })),
+    })
+}
+
+pub async fn sendpay_success_handler(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<(), Error> {
+    let sendpay_success = args
+        .get("sendpay_success")
+        .ok_or_else(|| anyhow!("sendpay_success: missing sendpay_success object"))?;
+    let payment_hash: Sha256 = serde_json::from_value(
+        sendpay_success
+            .get("payment_hash")
+            .ok_or_else(|| anyhow!("sendpay_success: missing payment_hash"))?
+            .clone(),
+    )?;
+    let preimage: Secret = serde_json::from_value(
+        sendpay_success
+            .get("payment_preimage")
+            .ok_or_else(|| anyhow!("sendpay_success: missing payment_preimage"))?
+            .clone(),
+    )?;
+
+    let Some((mut record, generation)) = load_lnurl_record(plugin.clone(), &payment_hash).await?
+    else {
+        return Ok(());
+    };
+    if record.settled {
+        return Ok(());
+    }
+    record.settled = true;
+    // Multi-part payments notify once per part, the generation makes sure only one wins
+    if let Err(e) =
+        save_lnurl_record(plugin.clone(), &payment_hash, &record, Some(generation)).await
+    {
+        log::debug!("successAction for {payment_hash} already handled: {e}");
+        return Ok(());
+    }
+
+    if let Some(success_action) = &record.success_action {
+        let mut success_action_json =
+            success_action_to_json(success_action, Some(&preimage.to_vec()));
+        success_action_json
+            .as_object_mut()
+            .unwrap()
+            .insert("payment_hash".to_owned(), json!(payment_hash.to_string()));
+        log::info!("LNURL successAction: {success_action_json}");
+        plugin
+            .send_custom_notification(NOTIFICATION_SUCCESS_ACTION.to_owned(), success_action_json)
+            .await?;
+    }
+    Ok(())
+}
+
+// Replies of bLIP-32 resolvers come back through the reply path of the query,
+// whose path_id is handed to us as the secret of the message
+pub async fn onion_message_handler(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let Some((path_id, dnssec_proof)) = dnssec_proof_reply(&args["onion_message"]) else {
+        return Ok(json!({"result":"continue"}));
+    };
+    deliver_dnssec_proof(&plugin.state().dnssec_queries, path_id, dnssec_proof);
+    Ok(json!({"result":"continue"}))
+}
+
 fn check_setconfig(param_val: ParamValue) -> Result<(), anyhow::Error> {
     let config;
     let mut val = None;
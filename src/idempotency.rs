@@ -0,0 +1,140 @@
+use std::path::Path;
+
+use anyhow::{Error, anyhow};
+use bitcoin::hex::DisplayHex;
+use cln_plugin::Plugin;
+use cln_rpc::{
+    ClnRpc,
+    model::responses::{ListsendpaysPayments, ListsendpaysPaymentsStatus},
+    primitives::Amount,
+};
+use serde_json::{Map, json};
+
+use crate::{
+    datastore::{load_idempotency_record, save_idempotency_record},
+    structs::{IdempotencyRecord, IdempotentCall, PluginState},
+    util::{invoice_payment_hash, local_payment_status},
+};
+
+pub fn idempotency_key_param(
+    params: &Map<String, serde_json::Value>,
+) -> Result<Option<String>, Error> {
+    let Some(key) = params.get("idempotency_key") else {
+        return Ok(None);
+    };
+    let key = key
+        .as_str()
+        .ok_or_else(|| anyhow!("`idempotency_key` must be a string"))?;
+    if key.is_empty() {
+        return Err(anyhow!("`idempotency_key` must not be empty"));
+    }
+    Ok(Some(key.to_owned()))
+}
+
+// A key that was seen before is answered from the invoice stored with it and the
+// local payment status of that invoice, so retries never fetch a new invoice
+pub async fn check_idempotency_key(
+    plugin: Plugin<PluginState>,
+    key: &str,
+) -> Result<IdempotentCall, Error> {
+    let Some(record) = load_idempotency_record(plugin.clone(), key).await? else {
+        return Ok(IdempotentCall::New);
+    };
+    let payment_hash = record.payment_hash;
+    let mut rpc = ClnRpc::new(
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file),
+    )
+    .await?;
+    let (status, payments) = local_payment_status(&mut rpc, payment_hash).await?;
+    let result = idempotent_result(key, &record, status, &payments);
+    log::info!("idempotency_key {key} already used for {payment_hash}: {status}");
+    Ok(match status {
+        "complete" => IdempotentCall::Complete(result),
+        "pending" => IdempotentCall::Pending(result),
+        _ => IdempotentCall::Retry(record, result),
+    })
+}
+
+// Offers have no payment hash to look up the payment status with, their invoice
+// must be fetched first
+pub async fn save_idempotent_invoice(
+    plugin: Plugin<PluginState>,
+    key: &str,
+    invoice: &str,
+    replace: bool,
+) -> Result<(), Error> {
+    let mut rpc = ClnRpc::new(
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file),
+    )
+    .await?;
+    let payment_hash = invoice_payment_hash(&mut rpc, invoice)
+        .await?
+        .ok_or_else(|| anyhow!("`idempotency_key` is only supported for invoices"))?;
+    let record = IdempotencyRecord {
+        invoice: invoice.to_owned(),
+        payment_hash,
+    };
+    save_idempotency_record(plugin, key, &record, replace)
+        .await
+        .map_err(|e| anyhow!("could not store idempotency_key {key}: {e}"))
+}
+
+fn idempotent_result(
+    key: &str,
+    record: &IdempotencyRecord,
+    status: &str,
+    payments: &[ListsendpaysPayments],
+) -> serde_json::Value {
+    let mut result = json!({
+        "idempotency_key": key,
+        "invoice": record.invoice,
+        "payment_hash": record.payment_hash.to_string(),
+        "status": status,
+    });
+    let complete: Vec<&ListsendpaysPayments> = payments
+        .iter()
+        .filter(|p| p.status == ListsendpaysPaymentsStatus::COMPLETE)
+        .collect();
+    if let Some(preimage) = complete.iter().find_map(|p| p.payment_preimage) {
+        let result_obj = result.as_object_mut().unwrap();
+        result_obj.insert(
+            "payment_preimage".to_owned(),
+            json!(preimage.to_vec().to_lower_hex_string()),
+        );
+        result_obj.insert(
+            "amount_msat".to_owned(),
+            json!(
+                complete
+                    .iter()
+                    .map(|p| p.amount_msat.unwrap_or(Amount::from_msat(0)).msat())
+                    .sum::<u64>()
+            ),
+        );
+        result_obj.insert(
+            "amount_sent_msat".to_owned(),
+            json!(
+                complete
+                    .iter()
+                    .map(|p| p.amount_sent_msat.msat())
+                    .sum::<u64>()
+            ),
+        );
+        result_obj.insert("parts".to_owned(), json!(complete.len()));
+    }
+    result
+}
+
+#[test]
+fn test_idempotency_key_param() {
+    let mut params = Map::new();
+    assert_eq!(idempotency_key_param(&params).unwrap(), None);
+    params.insert("idempotency_key".to_owned(), json!("job-42"));
+    assert_eq!(
+        idempotency_key_param(&params).unwrap().as_deref(),
+        Some("job-42")
+    );
+    params.insert("idempotency_key".to_owned(), json!(42));
+    assert!(idempotency_key_param(&params).is_err());
+    params.insert("idempotency_key".to_owned(), json!(""));
+    assert!(idempotency_key_param(&params).is_err());
+}
@@ -0,0 +1,416 @@
+use anyhow::anyhow;
+use cln_rpc::{
+    model::responses::{DecodeResponse, DecodeType},
+    primitives::{Amount, Sha256},
+};
+
+use crate::structs::{CommittedDescription, Config, LnurlpConfig};
+
+// BOLT11 default when the invoice has no expiry field
+const DEFAULT_INVOICE_EXPIRY: u64 = 3600;
+// BOLT12 default when the invoice has no relative_expiry field
+const DEFAULT_BOLT12_INVOICE_EXPIRY: u64 = 7200;
+// Same as the default `maxdelay` of xpay, anything above can not be routed anyway
+pub const MAX_FINAL_CLTV_EXPIRY: u32 = 2016;
+
+pub fn network_currency(network: &str) -> Option<&'static str> {
+    match network {
+        "bitcoin" => Some("bc"),
+        "testnet" | "testnet4" => Some("tb"),
+        "signet" => Some("tbs"),
+        "regtest" => Some("bcrt"),
+        _ => None,
+    }
+}
+
+// Catches invoices a buggy or malicious LNURL server hands us before they end up in xpay
+pub fn validate_lnurl_invoice(
+    invoice_decoded: &DecodeResponse,
+    lnurlp_config: &LnurlpConfig,
+    committed_description: &CommittedDescription,
+    amount_msat: Amount,
+    config: &Config,
+    now: u64,
+) -> Result<(), anyhow::Error> {
+    if invoice_decoded.item_type != DecodeType::BOLT11_INVOICE {
+        return Err(anyhow!(
+            "Lnurl: not a bolt11 invoice: {:?}",
+            invoice_decoded.item_type
+        ));
+    }
+    if !invoice_decoded.valid {
+        return Err(anyhow!("Lnurl: invoice is not valid!"));
+    }
+
+    if invoice_decoded.amount_msat.is_none() || invoice_decoded.amount_msat.unwrap() != amount_msat
+    {
+        return Err(anyhow!(
+            "Lnurl: wrong amount in invoice: {}!={}",
+            invoice_decoded.amount_msat.map_or(0, |a| a.msat()),
+            amount_msat.msat()
+        ));
+    }
+
+    if let Some(expected_currency) = network_currency(&config.network) {
+        let currency = invoice_decoded.currency.as_deref().unwrap_or_default();
+        if currency != expected_currency {
+            return Err(anyhow!(
+                "Lnurl: invoice is for the wrong network! {currency}!={expected_currency} ({})",
+                config.network
+            ));
+        }
+    }
+
+    let created_at = invoice_decoded
+        .created_at
+        .ok_or_else(|| anyhow!("Lnurl: invoice is missing created_at"))?;
+    let expires_at =
+        created_at.saturating_add(invoice_decoded.expiry.unwrap_or(DEFAULT_INVOICE_EXPIRY));
+    if expires_at <= now {
+        return Err(anyhow!("Lnurl: invoice expired {}s ago!", now - expires_at));
+    }
+    if expires_at - now < config.invoice_expiry_margin {
+        return Err(anyhow!(
+            "Lnurl: invoice expires too soon! {}s<{}s",
+            expires_at - now,
+            config.invoice_expiry_margin
+        ));
+    }
+
+    if let Some(min_final_cltv_expiry) = invoice_decoded.min_final_cltv_expiry {
+        if min_final_cltv_expiry > MAX_FINAL_CLTV_EXPIRY {
+            return Err(anyhow!(
+                "Lnurl: invoice min_final_cltv_expiry too high! \
+                {min_final_cltv_expiry}>{MAX_FINAL_CLTV_EXPIRY}"
+            ));
+        }
+    }
+
+    let metadata = match committed_description {
+        // NIP-57: a zap invoice must commit to the zap request, not to the metadata
+        CommittedDescription::ZapRequest(zap_request) => {
+            let zap_request_hashed = Sha256::const_hash(zap_request.as_bytes());
+            let description_hash = invoice_decoded.description_hash.ok_or_else(|| {
+                anyhow!("Lnurl: zap invoice is missing the description hash of the zap request!")
+            })?;
+            if description_hash != zap_request_hashed {
+                return Err(anyhow!(
+                    "Lnurl: description hash not matching zap request! \
+                    {zap_request_hashed} != {description_hash}"
+                ));
+            }
+            return Ok(());
+        }
+        CommittedDescription::Metadata(metadata) => metadata,
+    };
+    if let Some(description_hash) = invoice_decoded.description_hash {
+        let metadata_hashed = Sha256::const_hash(metadata.as_bytes());
+        log::debug!("Lnurl: metadata_hashed:{metadata_hashed} description_hash:{description_hash}");
+        if description_hash != metadata_hashed {
+            return Err(anyhow!(
+                "Lnurl: description hash not matching metadata! {metadata_hashed} != {description_hash}"
+            ));
+        }
+    } else {
+        if config.strict_lnurl {
+            return Err(anyhow!("Strict mode: Lnurl: missing description hash!"));
+        }
+        // Some servers are not including a description hash
+        log::info!(
+            "Lnurl: missing description hash, please report to lnaddress \
+            service provider they are violating the spec in LUD-06"
+        );
+        if let Some(short_description) = &lnurlp_config.parsed_metadata.description {
+            let description = invoice_decoded.description.as_deref().unwrap_or_default();
+            if description != short_description {
+                return Err(anyhow!(
+                    "Lnurl: invoice description not matching metadata text/plain! \
+                    {description} != {short_description}"
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// fetchinvoice checks the invoice against our request already, this makes sure
+// the invoice handed out by payany is for the offer, amount and note we asked for
+pub fn validate_offer_invoice(
+    invoice_decoded: &DecodeResponse,
+    offer_id: &str,
+    amount_msat: Option<Amount>,
+    payer_note: Option<&str>,
+    config: &Config,
+    now: u64,
+) -> Result<(), anyhow::Error> {
+    if invoice_decoded.item_type != DecodeType::BOLT12_INVOICE {
+        return Err(anyhow!(
+            "Offer: not a bolt12 invoice: {:?}",
+            invoice_decoded.item_type
+        ));
+    }
+    if !invoice_decoded.valid {
+        return Err(anyhow!("Offer: invoice is not valid!"));
+    }
+    if invoice_decoded.offer_id.as_deref() != Some(offer_id) {
+        return Err(anyhow!(
+            "Offer: invoice is for another offer: {}!={offer_id}",
+            invoice_decoded.offer_id.as_deref().unwrap_or_default()
+        ));
+    }
+    if let Some(amount_msat) = amount_msat {
+        if invoice_decoded.invoice_amount_msat != Some(amount_msat) {
+            return Err(anyhow!(
+                "Offer: wrong amount in invoice: {}!={}",
+                invoice_decoded.invoice_amount_msat.map_or(0, |a| a.msat()),
+                amount_msat.msat()
+            ));
+        }
+    }
+    if invoice_decoded.invreq_payer_note.as_deref() != payer_note {
+        return Err(anyhow!("Offer: invoice has a different payer_note!"));
+    }
+
+    let created_at = invoice_decoded
+        .invoice_created_at
+        .ok_or_else(|| anyhow!("Offer: invoice is missing created_at"))?;
+    let expires_at = created_at.saturating_add(
+        invoice_decoded
+            .invoice_relative_expiry
+            .map_or(DEFAULT_BOLT12_INVOICE_EXPIRY, u64::from),
+    );
+    if expires_at <= now {
+        return Err(anyhow!("Offer: invoice expired {}s ago!", now - expires_at));
+    }
+    if expires_at - now < config.invoice_expiry_margin {
+        return Err(anyhow!(
+            "Offer: invoice expires too soon! {}s<{}s",
+            expires_at - now,
+            config.invoice_expiry_margin
+        ));
+    }
+
+    Ok(())
+}
+
+// A decoded invoice with some of its fields replaced by `changes`
+#[cfg(test)]
+fn decoded_invoice(mut invoice: serde_json::Value, changes: serde_json::Value) -> DecodeResponse {
+    for (key, value) in changes.as_object().unwrap() {
+        invoice[key] = value.clone();
+    }
+    serde_json::from_value(invoice).unwrap()
+}
+
+#[test]
+fn test_validate_lnurl_invoice() {
+    use serde_json::json;
+
+    use crate::structs::LnurlMetadata;
+
+    let now = 1_700_000_000;
+    let lnurlp_config = LnurlpConfig {
+        callback: "https://site.com/cb".to_owned(),
+        max_sendable: 10_000,
+        min_sendable: 1_000,
+        metadata: json!([["text/plain", "coffee"]]).to_string(),
+        tag: "payRequest".to_owned(),
+        comment_allowed: None,
+        payer_data: None,
+        disposable: None,
+        allows_nostr: None,
+        nostr_pubkey: None,
+        parsed_metadata: LnurlMetadata {
+            description: Some("coffee".to_owned()),
+            ..Default::default()
+        },
+    };
+    let config = Config {
+        network: "regtest".to_owned(),
+        invoice_expiry_margin: 60,
+        ..Default::default()
+    };
+    let decoded = |changes: serde_json::Value| {
+        decoded_invoice(
+            json!({
+                "type": "bolt11 invoice",
+                "valid": true,
+                "currency": "bcrt",
+                "created_at": now - 100,
+                "expiry": 3600,
+                "amount_msat": 5_000,
+                "min_final_cltv_expiry": 18,
+                "description": "coffee",
+            }),
+            changes,
+        )
+    };
+    let metadata = CommittedDescription::Metadata(lnurlp_config.metadata.clone());
+    let validate = |invoice: &DecodeResponse| {
+        validate_lnurl_invoice(
+            invoice,
+            &lnurlp_config,
+            &metadata,
+            Amount::from_msat(5_000),
+            &config,
+            now,
+        )
+        .map_err(|e| e.to_string())
+    };
+
+    assert!(validate(&decoded(json!({}))).is_ok());
+    assert!(
+        validate(&decoded(json!({"valid": false})))
+            .unwrap_err()
+            .contains("not valid")
+    );
+    assert!(
+        validate(&decoded(json!({"amount_msat": 4_000})))
+            .unwrap_err()
+            .contains("wrong amount")
+    );
+    assert!(
+        validate(&decoded(json!({"currency": "tb"})))
+            .unwrap_err()
+            .contains("wrong network")
+    );
+    assert!(
+        validate(&decoded(json!({"created_at": now - 4000})))
+            .unwrap_err()
+            .contains("expired 400s ago")
+    );
+    assert!(
+        validate(&decoded(json!({"created_at": now - 3570})))
+            .unwrap_err()
+            .contains("expires too soon")
+    );
+    assert!(
+        validate(&decoded(json!({"min_final_cltv_expiry": 5000})))
+            .unwrap_err()
+            .contains("min_final_cltv_expiry too high")
+    );
+    assert!(
+        validate(&decoded(json!({"description": "tea"})))
+            .unwrap_err()
+            .contains("not matching metadata text/plain")
+    );
+    assert!(
+        validate(&decoded(
+            json!({"description_hash": Sha256::const_hash(b"other").to_string()})
+        ))
+        .unwrap_err()
+        .contains("description hash not matching")
+    );
+    assert!(
+        validate(&decoded(json!({
+            "description_hash": Sha256::const_hash(lnurlp_config.metadata.as_bytes()).to_string()
+        })))
+        .is_ok()
+    );
+
+    let zap_request = r#"{"kind":9734,"content":"","tags":[]}"#;
+    let validate_zap = |invoice: &DecodeResponse| {
+        validate_lnurl_invoice(
+            invoice,
+            &lnurlp_config,
+            &CommittedDescription::ZapRequest(zap_request.to_owned()),
+            Amount::from_msat(5_000),
+            &config,
+            now,
+        )
+        .map_err(|e| e.to_string())
+    };
+    assert!(
+        validate_zap(&decoded(json!({
+            "description_hash": Sha256::const_hash(zap_request.as_bytes()).to_string()
+        })))
+        .is_ok()
+    );
+    assert!(
+        validate_zap(&decoded(json!({})))
+            .unwrap_err()
+            .contains("missing the description hash of the zap request")
+    );
+    assert!(
+        validate_zap(&decoded(json!({
+            "description_hash": Sha256::const_hash(lnurlp_config.metadata.as_bytes()).to_string()
+        })))
+        .unwrap_err()
+        .contains("description hash not matching zap request")
+    );
+}
+
+#[test]
+fn test_validate_offer_invoice() {
+    use serde_json::json;
+
+    let now = 1_700_000_000;
+    let config = Config {
+        invoice_expiry_margin: 60,
+        ..Default::default()
+    };
+    let decoded = |changes: serde_json::Value| {
+        decoded_invoice(
+            json!({
+                "type": "bolt12 invoice",
+                "valid": true,
+                "offer_id": "aa".repeat(32),
+                "invoice_amount_msat": 5_000,
+                "invreq_payer_note": "coffee",
+                "invoice_created_at": now - 100,
+            }),
+            changes,
+        )
+    };
+    let validate = |invoice: &DecodeResponse, amount_msat: Option<u64>| {
+        validate_offer_invoice(
+            invoice,
+            &"aa".repeat(32),
+            amount_msat.map(Amount::from_msat),
+            Some("coffee"),
+            &config,
+            now,
+        )
+        .map_err(|e| e.to_string())
+    };
+
+    assert!(validate(&decoded(json!({})), Some(5_000)).is_ok());
+    assert!(validate(&decoded(json!({"invoice_amount_msat": 4_000})), None).is_ok());
+    assert!(
+        validate(&decoded(json!({"type": "bolt12 offer"})), Some(5_000))
+            .unwrap_err()
+            .contains("not a bolt12 invoice")
+    );
+    assert!(
+        validate(&decoded(json!({"offer_id": "bb".repeat(32)})), Some(5_000))
+            .unwrap_err()
+            .contains("another offer")
+    );
+    assert!(
+        validate(&decoded(json!({"invoice_amount_msat": 4_000})), Some(5_000))
+            .unwrap_err()
+            .contains("wrong amount")
+    );
+    assert!(
+        validate(&decoded(json!({"invreq_payer_note": "tea"})), Some(5_000))
+            .unwrap_err()
+            .contains("different payer_note")
+    );
+    assert!(
+        validate(
+            &decoded(json!({"invoice_created_at": now - 7300})),
+            Some(5_000)
+        )
+        .unwrap_err()
+        .contains("expired 100s ago")
+    );
+    assert!(
+        validate(
+            &decoded(json!({"invoice_relative_expiry": 120})),
+            Some(5_000)
+        )
+        .unwrap_err()
+        .contains("expires too soon")
+    );
+}
@@ -0,0 +1,91 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+use bitcoin::{
+    NetworkKind,
+    bip32::{ChildNumber, Xpriv},
+    hashes::{Hash, HashEngine, Hmac, HmacEngine, sha256},
+    hex::{DisplayHex, FromHex},
+    secp256k1::{Message, PublicKey, Secp256k1, SecretKey},
+};
+use cln_plugin::Plugin;
+use cln_rpc::{ClnRpc, model::requests::MakesecretRequest};
+
+use crate::structs::PluginState;
+
+const LINKING_KEY_SECRET: &str = "payany lnurl linking key";
+
+pub async fn linking_key(
+    plugin: Plugin<PluginState>,
+    domain: &str,
+) -> Result<SecretKey, anyhow::Error> {
+    let mut rpc = ClnRpc::new(
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file),
+    )
+    .await?;
+
+    let seed = rpc
+        .call_typed(&MakesecretRequest {
+            hex: None,
+            string: Some(LINKING_KEY_SECRET.to_owned()),
+        })
+        .await?
+        .secret;
+
+    derive_linking_key(&seed.to_vec(), domain)
+}
+
+// LUD-05: m/138'/0 is the hashing key, the linking key is at
+// m/138'/<long1>/<long2>/<long3>/<long4> with the longs taken from
+// hmacSha256(hashingKey, domain)
+fn derive_linking_key(seed: &[u8], domain: &str) -> Result<SecretKey, anyhow::Error> {
+    let secp = Secp256k1::new();
+    let master = Xpriv::new_master(NetworkKind::Main, seed)?;
+    let hashing_key = master.derive_priv(
+        &secp,
+        &[
+            ChildNumber::from_hardened_idx(138)?,
+            ChildNumber::from_normal_idx(0)?,
+        ],
+    )?;
+
+    let mut engine = HmacEngine::<sha256::Hash>::new(&hashing_key.private_key.secret_bytes());
+    engine.input(domain.as_bytes());
+    let derivation_material = Hmac::<sha256::Hash>::from_engine(engine).to_byte_array();
+
+    let mut path = vec![ChildNumber::from_hardened_idx(138)?];
+    for chunk in derivation_material[..16].chunks_exact(4) {
+        path.push(ChildNumber::from(u32::from_be_bytes(chunk.try_into()?)));
+    }
+    Ok(master.derive_priv(&secp, &path)?.private_key)
+}
+
+pub fn sign_k1(linking_key: &SecretKey, k1: &str) -> Result<(String, String), anyhow::Error> {
+    let secp = Secp256k1::new();
+    let k1_bytes = <[u8; 32]>::from_hex(k1).map_err(|e| anyhow!("k1 is not 32 bytes hex: {e}"))?;
+    let signature = secp.sign_ecdsa(&Message::from_digest(k1_bytes), linking_key);
+    let pubkey = PublicKey::from_secret_key(&secp, linking_key);
+    Ok((
+        pubkey.serialize().to_lower_hex_string(),
+        signature.serialize_der().to_lower_hex_string(),
+    ))
+}
+
+#[test]
+fn test_derive_linking_key() {
+    let seed = [1u8; 32];
+    let key_a = derive_linking_key(&seed, "site.com").unwrap();
+    let key_b = derive_linking_key(&seed, "site.com").unwrap();
+    let key_c = derive_linking_key(&seed, "other.site.com").unwrap();
+    assert_eq!(key_a, key_b);
+    assert_ne!(key_a, key_c);
+
+    let (pubkey, sig) = sign_k1(
+        &key_a,
+        "e2af6254a8df433264fa23f67eb8188635d15ce883e8fc020989d5f82ae6f11e",
+    )
+    .unwrap();
+    assert_eq!(pubkey.len(), 66);
+    assert!(sign_k1(&key_a, "abcd").is_err());
+    assert!(!sig.is_empty());
+}
@@ -0,0 +1,124 @@
+use anyhow::anyhow;
+use serde_json::{Map, json};
+
+use crate::util::check_strict;
+
+// Services in the wild send amounts as strings, omit `routes` or use their own
+// casing for the tag. Outside of strict mode these are fixed up (and logged)
+// before deserializing, in strict mode each of them is an error.
+pub fn normalize_lnurlp_config(
+    config_json: &mut serde_json::Value,
+    strict_lnurl: bool,
+) -> Result<(), anyhow::Error> {
+    let config_obj = config_json
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("Not a valid LNURL config response: not an object"))?;
+    for field in ["minSendable", "maxSendable", "commentAllowed"] {
+        normalize_integer(config_obj, field, "config", strict_lnurl)?;
+    }
+    if let Some(tag) = config_obj.get("tag").and_then(|t| t.as_str()) {
+        if tag != "payRequest" && tag.eq_ignore_ascii_case("payRequest") {
+            check_strict(
+                strict_lnurl,
+                &format!("config `tag` is `{tag}` instead of `payRequest`"),
+            )?;
+            config_obj.insert("tag".to_owned(), json!("payRequest"));
+        }
+    }
+    Ok(())
+}
+
+pub fn normalize_lnurlp_callback(
+    callback_json: &mut serde_json::Value,
+    strict_lnurl: bool,
+) -> Result<(), anyhow::Error> {
+    let callback_obj = callback_json
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("Not a valid LNURL invoice response: not an object"))?;
+    match callback_obj.get("routes") {
+        Some(serde_json::Value::Array(_)) => (),
+        None | Some(serde_json::Value::Null) => {
+            check_strict(strict_lnurl, "invoice response is missing `routes`")?;
+            callback_obj.insert("routes".to_owned(), json!([]));
+        }
+        Some(routes) => {
+            check_strict(
+                strict_lnurl,
+                &format!("invoice response `routes` is not an array: {routes}"),
+            )?;
+            callback_obj.insert("routes".to_owned(), json!([]));
+        }
+    }
+    Ok(())
+}
+
+fn normalize_integer(
+    obj: &mut Map<String, serde_json::Value>,
+    field: &str,
+    what: &str,
+    strict_lnurl: bool,
+) -> Result<(), anyhow::Error> {
+    let Some(value) = obj.get(field) else {
+        return Ok(());
+    };
+    if value.is_u64() {
+        return Ok(());
+    }
+    let integer = match value {
+        serde_json::Value::String(s) => s.trim().parse::<u64>().ok(),
+        serde_json::Value::Number(n) => n
+            .as_f64()
+            .filter(|f| f.fract() == 0.0 && *f >= 0.0 && *f <= u64::MAX as f64)
+            .map(|f| f as u64),
+        _ => None,
+    }
+    .ok_or_else(|| anyhow!("Lnurl: {what} `{field}` is not an integer: {value}"))?;
+    check_strict(
+        strict_lnurl,
+        &format!("{what} `{field}` is not a json integer: {value}"),
+    )?;
+    obj.insert(field.to_owned(), json!(integer));
+    Ok(())
+}
+
+#[test]
+fn test_normalize_lnurlp() {
+    let mut config = json!({
+        "callback": "https://site.com/cb",
+        "minSendable": "1000",
+        "maxSendable": 100_000.0,
+        "commentAllowed": 32,
+        "metadata": "[]",
+        "tag": "PAYREQUEST",
+    });
+    assert!(
+        normalize_lnurlp_config(&mut config.clone(), true)
+            .unwrap_err()
+            .to_string()
+            .contains("`minSendable` is not a json integer")
+    );
+    normalize_lnurlp_config(&mut config, false).unwrap();
+    assert_eq!(config["minSendable"], json!(1000));
+    assert_eq!(config["maxSendable"], json!(100_000));
+    assert_eq!(config["commentAllowed"], json!(32));
+    assert_eq!(config["tag"], json!("payRequest"));
+
+    let mut tag_only = json!({"minSendable": 1, "maxSendable": 2, "tag": "payrequest"});
+    assert!(
+        normalize_lnurlp_config(&mut tag_only, true)
+            .unwrap_err()
+            .to_string()
+            .contains("`tag` is `payrequest`")
+    );
+
+    let mut bad_amount = json!({"minSendable": "lots"});
+    assert!(normalize_lnurlp_config(&mut bad_amount, false).is_err());
+
+    let mut callback = json!({"pr": "lnbc1"});
+    assert!(normalize_lnurlp_callback(&mut callback.clone(), true).is_err());
+    normalize_lnurlp_callback(&mut callback, false).unwrap();
+    assert_eq!(callback["routes"], json!([]));
+
+    let mut conforming = json!({"pr": "lnbc1", "routes": []});
+    normalize_lnurlp_callback(&mut conforming, true).unwrap();
+}
@@ -1,23 +1,55 @@
-use std::{fmt::Write as _, path::Path, time::Duration};
+use std::{path::Path, time::Duration};
 
 use anyhow::{Context, Error, anyhow};
+use bech32::{
+    Bech32,
+    Checksum,
+    primitives::decode::{ChecksumError, UncheckedHrpstring},
+};
+use bitcoin::{
+    hex::DisplayHex,
+    secp256k1::{PublicKey, Secp256k1},
+};
+use chrono::Utc;
 use cln_plugin::Plugin;
-use cln_rpc::{
-    ClnRpc,
-    model::requests::DecodeRequest,
-    primitives::{Amount, Sha256},
+use cln_rpc::{ClnRpc, model::requests::DecodeRequest, primitives::Amount};
+use serde_json::{Map, json};
+
+use crate::{
+    OPT_PAYANY_NOSTR_KEY,
+    OPT_PAYANY_PAYER_IDENTIFIER,
+    OPT_PAYANY_PAYER_NAME,
+    datastore::{load_lnurl_record, save_lnurl_record},
+    invoice::validate_lnurl_invoice,
+    keys::{linking_key, sign_k1},
+    lenient::{normalize_lnurlp_callback, normalize_lnurlp_config},
+    metadata::parse_metadata,
+    nostr::{build_zap_request, parse_nostr_pubkey},
+    structs::{
+        AddressResolutionError,
+        CommittedDescription,
+        Config,
+        LUD17_SCHEMES,
+        LnurlInvoiceRecord,
+        LnurlServiceError,
+        LnurlTag,
+        LnurlVerify,
+        LnurlpCallback,
+        LnurlpConfig,
+        PayerDataRequest,
+        PayerInfo,
+        PluginState,
+        Resolution,
+        URI_SCHEMES,
+        ZapInfo,
+    },
+    successaction::parse_success_action,
+    util::{earlier_payment_by_hash, strip_prefix_ignore_ascii_case},
 };
-use serde_json::Map;
 
-use crate::structs::{Config, LnurlpCallback, LnurlpConfig, PluginState};
+const LNURL_MAX_LENGTH: usize = 8192;
 
-pub async fn try_fetch_lnurl(
-    config: &Config,
-    lnaddress: Option<&str>,
-    config_url: String,
-    amount_msat: Amount,
-    message: Option<String>,
-) -> Result<(LnurlpCallback, LnurlpConfig), Error> {
+pub fn build_client(config: &Config) -> Result<reqwest::Client, Error> {
     let client = if let Some(tp) = &config.tor_proxy {
         let proxy = reqwest::Proxy::all(format!("socks5h://{tp}"))?;
         reqwest::Client::builder()
@@ -29,62 +61,275 @@ pub async fn try_fetch_lnurl(
             .timeout(Duration::from_secs(30))
             .build()?
     };
-    let lnurlp_config_raw = match client.get(config_url).send().await {
+    Ok(client)
+}
+
+pub async fn try_fetch_lnurl(
+    plugin: Plugin<PluginState>,
+    config: &Config,
+    lnaddress: Option<&str>,
+    config_url: String,
+    amount_msat: Option<Amount>,
+    payer: PayerInfo,
+) -> Result<(LnurlpCallback, LnurlpConfig, CommittedDescription, Amount), Error> {
+    let lnurlp_config = fetch_lnurlp_config(config, &config_url).await?;
+    let amount_msat = lnurlp_amount(&lnurlp_config, amount_msat)?;
+    let (callback_response, committed_description) = fetch_lnurlp_invoice(
+        plugin,
+        config,
+        &lnurlp_config,
+        lnaddress,
+        &config_url,
+        amount_msat,
+        payer,
+    )
+    .await?;
+    Ok((
+        callback_response,
+        lnurlp_config,
+        committed_description,
+        amount_msat,
+    ))
+}
+
+pub async fn fetch_lnurlp_config(config: &Config, config_url: &str) -> Result<LnurlpConfig, Error> {
+    let client = build_client(config)?;
+    let mut lnurlp_config_json = match fetch_lnurl_json(&client, config_url, "lnurl config").await {
         Ok(o) => o,
         Err(e) => {
-            log::warn!("LNURL: failed to fetch lnurl config: {:?}", e);
-            return Err(anyhow!(e));
+            log::warn!("LNURL: failed to fetch lnurl config: {e}");
+            return Err(e);
         }
     };
-    if !lnurlp_config_raw.status().is_success() {
-        return Err(anyhow!(
-            "LNURL: got bad status for lnurl config: {}",
-            lnurlp_config_raw.status()
-        ));
+    log::debug!("lnurl config: {lnurlp_config_json}");
+    if let Some(tag) = lnurlp_config_json
+        .get("tag")
+        .and_then(|t| t.as_str())
+        .and_then(|t| t.parse::<LnurlTag>().ok())
+    {
+        log::debug!("LNURL: detected {}", tag.protocol());
+        if tag != LnurlTag::PayRequest {
+            return Err(unpayable_tag_error(tag, config_url));
+        }
     }
-    log::debug!("lnurl config: {lnurlp_config_raw:?}");
-    let lnurlp_config = lnurlp_config_raw
-        .json::<LnurlpConfig>()
-        .await
-        .context("Not a valid LNURL config response")?;
+    normalize_lnurlp_config(&mut lnurlp_config_json, config.strict_lnurl)?;
+    let mut lnurlp_config: LnurlpConfig =
+        serde_json::from_value(lnurlp_config_json).context("Not a valid LNURL config response")?;
+    lnurlp_config.parsed_metadata = parse_metadata(&lnurlp_config.metadata, config.strict_lnurl)?;
+    Ok(lnurlp_config)
+}
 
-    validate_lnurl_config(&lnurlp_config, amount_msat, lnaddress, config.strict_lnurl)?;
+// Services with `minSendable == maxSendable` only accept that one amount, so
+// there is no need to make the user repeat it
+pub fn lnurlp_amount(
+    lnurlp_config: &LnurlpConfig,
+    amount_msat: Option<Amount>,
+) -> Result<Amount, Error> {
+    if let Some(amt) = amount_msat {
+        return Ok(amt);
+    }
+    if lnurlp_config.min_sendable == lnurlp_config.max_sendable {
+        log::debug!(
+            "lnurl: fixed amount of {}msat used",
+            lnurlp_config.min_sendable
+        );
+        return Ok(Amount::from_msat(lnurlp_config.min_sendable));
+    }
+    Err(anyhow!(
+        "lnurl: missing amount_msat, service accepts {}msat to {}msat",
+        lnurlp_config.min_sendable,
+        lnurlp_config.max_sendable
+    ))
+}
 
-    let mut callback_url = format!("{}?amount={}", lnurlp_config.callback, amount_msat.msat());
-    if let Some(msg) = message {
+pub async fn fetch_lnurlp_invoice(
+    plugin: Plugin<PluginState>,
+    config: &Config,
+    lnurlp_config: &LnurlpConfig,
+    lnaddress: Option<&str>,
+    config_url: &str,
+    amount_msat: Amount,
+    payer: PayerInfo,
+) -> Result<(LnurlpCallback, CommittedDescription), Error> {
+    let client = build_client(config)?;
+    validate_lnurl_config(lnurlp_config, amount_msat, lnaddress, config.strict_lnurl)?;
+
+    let mut callback_url = parse_callback_url(&lnurlp_config.callback)?;
+    callback_url
+        .query_pairs_mut()
+        .append_pair("amount", &amount_msat.msat().to_string());
+    if let Some(msg) = payer.message.as_ref() {
         let comment_length = lnurlp_config
             .comment_allowed
             .ok_or_else(|| anyhow!("LNURL: message not supported for this address!"))?;
-        if comment_length >= (msg.len() as u64) {
-            write!(callback_url, "&comment={msg}")?;
+        // LUD-12: commentAllowed is the number of characters, not bytes
+        let msg_length = msg.chars().count() as u64;
+        if comment_length >= msg_length {
+            callback_url.query_pairs_mut().append_pair("comment", msg);
         } else {
             return Err(anyhow!(
-                "LNURL: message too long for this address! {}>{}",
-                msg.len(),
-                comment_length
+                "LNURL: message too long for this address! {msg_length}>{comment_length}"
             ));
         }
     }
-    let callback_response_raw = client.get(callback_url).send().await?;
-    if !callback_response_raw.status().is_success() {
+    let payer_data = if let Some(payer_data_request) = &lnurlp_config.payer_data {
+        let domain = url::Url::parse(config_url)?
+            .host_str()
+            .ok_or_else(|| anyhow!("LNURL: no domain in url: {config_url}"))?
+            .to_owned();
+        build_payer_data(plugin, payer_data_request, &payer, &domain).await?
+    } else {
+        None
+    };
+    if let Some(pd) = &payer_data {
+        log::debug!("LNURL: sending payerdata: {pd}");
+        callback_url.query_pairs_mut().append_pair("payerdata", pd);
+    }
+    // NIP-57: zap invoices commit to the zap request instead of the metadata
+    let zap_request = if let Some(zap) = &payer.zap {
+        Some(build_lnurl_zap_request(
+            config,
+            lnurlp_config,
+            zap,
+            amount_msat,
+            config_url,
+        )?)
+    } else {
+        None
+    };
+    let committed_description = if let Some(zr) = &zap_request {
+        log::debug!("LNURL: sending zap request: {zr}");
+        callback_url.query_pairs_mut().append_pair("nostr", zr);
+        CommittedDescription::ZapRequest(zr.clone())
+    } else {
+        // LUD-18: the payerdata we sent is committed to in the description hash as well
+        CommittedDescription::Metadata(format!(
+            "{}{}",
+            lnurlp_config.metadata,
+            payer_data.as_deref().unwrap_or_default()
+        ))
+    };
+
+    log::debug!("LNURL: callback url: {callback_url}");
+    let mut callback_response_json =
+        fetch_lnurl_json(&client, callback_url.as_str(), "invoice").await?;
+    normalize_lnurlp_callback(&mut callback_response_json, config.strict_lnurl)?;
+    let callback_response: LnurlpCallback = serde_json::from_value(callback_response_json)
+        .context("Not a valid LNURL invoice response")?;
+    Ok((callback_response, committed_description))
+}
+
+// LUD-06: the callback may already carry a query, our parameters are appended to it
+// with `query_pairs_mut`
+pub fn parse_callback_url(callback: &str) -> Result<url::Url, Error> {
+    url::Url::parse(callback).map_err(|e| anyhow!("LNURL: invalid callback url: {e}"))
+}
+
+fn build_lnurl_zap_request(
+    config: &Config,
+    lnurlp_config: &LnurlpConfig,
+    zap: &ZapInfo,
+    amount_msat: Amount,
+    config_url: &str,
+) -> Result<String, Error> {
+    if lnurlp_config.allows_nostr != Some(true) {
+        return Err(anyhow!("LNURL: this address does not allow nostr zaps!"));
+    }
+    let nostr_pubkey = lnurlp_config
+        .nostr_pubkey
+        .as_deref()
+        .ok_or_else(|| anyhow!("LNURL: zap address is missing `nostrPubkey`"))?;
+    parse_nostr_pubkey(nostr_pubkey)
+        .map_err(|e| anyhow!("LNURL: invalid `nostrPubkey` of zap address: {e}"))?;
+    let keypair = config
+        .nostr_key
+        .as_ref()
+        .ok_or_else(|| anyhow!("zap: `{OPT_PAYANY_NOSTR_KEY}` is not set"))?;
+    build_zap_request(
+        keypair,
+        zap,
+        amount_msat.msat(),
+        config_url,
+        Utc::now().timestamp(),
+    )
+}
+
+async fn build_payer_data(
+    plugin: Plugin<PluginState>,
+    payer_data_request: &PayerDataRequest,
+    payer: &PayerInfo,
+    domain: &str,
+) -> Result<Option<String>, Error> {
+    let mut payer_data = Map::new();
+
+    if let Some(name_field) = &payer_data_request.name {
+        if let Some(name) = &payer.name {
+            payer_data.insert("name".to_owned(), json!(name));
+        } else if name_field.mandatory {
+            return Err(anyhow!(
+                "LNURL: service requires payerData `name`, set `{OPT_PAYANY_PAYER_NAME}` \
+                or `payer_name`"
+            ));
+        }
+    }
+    if let Some(identifier_field) = &payer_data_request.identifier {
+        if let Some(identifier) = &payer.identifier {
+            payer_data.insert("identifier".to_owned(), json!(identifier));
+        } else if identifier_field.mandatory {
+            return Err(anyhow!(
+                "LNURL: service requires payerData `identifier`, set \
+                `{OPT_PAYANY_PAYER_IDENTIFIER}` or `payer_identifier`"
+            ));
+        }
+    }
+    if payer_data_request
+        .email
+        .as_ref()
+        .is_some_and(|e| e.mandatory)
+    {
         return Err(anyhow!(
-            "LNURL: got bad status for invoice: {}",
-            callback_response_raw.status()
+            "LNURL: service requires payerData `email`, which is not supported"
         ));
     }
-    let callback_response = callback_response_raw.json::<LnurlpCallback>().await?;
-    Ok((callback_response, lnurlp_config))
+    if payer_data_request.pubkey.is_some() || payer_data_request.auth.is_some() {
+        let linking_key = linking_key(plugin, domain).await?;
+        if let Some(auth_field) = &payer_data_request.auth {
+            let (key, sig) = sign_k1(&linking_key, &auth_field.k1)?;
+            payer_data.insert("pubkey".to_owned(), json!(key));
+            payer_data.insert(
+                "auth".to_owned(),
+                json!({"key":key,"k1":auth_field.k1,"sig":sig}),
+            );
+        } else {
+            let secp = Secp256k1::signing_only();
+            payer_data.insert(
+                "pubkey".to_owned(),
+                json!(
+                    PublicKey::from_secret_key(&secp, &linking_key)
+                        .serialize()
+                        .to_lower_hex_string()
+                ),
+            );
+        }
+    }
+
+    if payer_data.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::to_string(&payer_data)?))
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn process_lnurl_invoice(
     plugin: Plugin<PluginState>,
     invstring_name: &str,
     callback_response: LnurlpCallback,
     lnurlp_config: LnurlpConfig,
+    committed_description: CommittedDescription,
     amount_msat: Amount,
     config: &Config,
     params: &mut Map<String, serde_json::Value>,
-) -> Result<(), Error> {
+) -> Result<Resolution, Error> {
     let mut rpc = ClnRpc::new(
         Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file),
     )
@@ -95,42 +340,150 @@ pub async fn process_lnurl_invoice(
             string: callback_response.pr.clone(),
         })
         .await?;
-    if invoice_decoded.amount_msat.is_none() || invoice_decoded.amount_msat.unwrap() != amount_msat
-    {
-        return Err(anyhow!(
-            "Lnurl: wrong amount in invoice: {}!={}",
-            invoice_decoded.amount_msat.map_or(0, |a| a.msat()),
-            amount_msat.msat()
-        ));
+    validate_lnurl_invoice(
+        &invoice_decoded,
+        &lnurlp_config,
+        &committed_description,
+        amount_msat,
+        config,
+        Utc::now().timestamp().try_into()?,
+    )?;
+
+    let success_action = if let Some(sa) = callback_response.success_action {
+        parse_success_action(&lnurlp_config.callback, sa, config.strict_lnurl)?
+    } else {
+        None
+    };
+
+    // LUD-11: `disposable` belongs to the callback response, but some services
+    // only announce it in the first response
+    let disposable = callback_response
+        .disposable
+        .or(lnurlp_config.disposable)
+        .unwrap_or(true);
+    if !disposable {
+        log::debug!("Lnurl: invoice is not disposable, storing it");
     }
-    if invoice_decoded.description_hash.is_none() {
-        if config.strict_lnurl {
-            return Err(anyhow!("Strict mode: Lnurl: missing description hash!"));
+
+    if success_action.is_some() || callback_response.verify.is_some() || !disposable {
+        let payment_hash = invoice_decoded
+            .payment_hash
+            .ok_or_else(|| anyhow!("Lnurl: invoice is missing a payment hash"))?;
+        // LUD-11: services may hand out a non-disposable invoice again
+        if let Some((payment_hash, status, _payments)) =
+            earlier_payment_by_hash(plugin.clone(), &mut rpc, payment_hash).await?
+        {
+            return Err(anyhow!(if status == "complete" {
+                format!("lnurl: invoice {payment_hash} was already paid")
+            } else {
+                format!("lnurl: invoice {payment_hash} is already being paid")
+            }));
         }
-        // Some servers are not including a description hash
-        log::info!(
-            "Lnurl: missing description hash, please report to lnaddress \
-            service provider they are violating the spec in LUD-06"
-        );
-    } else {
-        let metadata_hashed = Sha256::const_hash(lnurlp_config.metadata.as_bytes());
-        log::debug!(
-            "Lnurl: metadata_hashed:{} description_hash:{}",
-            metadata_hashed,
-            invoice_decoded.description_hash.unwrap()
-        );
-        if invoice_decoded.description_hash.unwrap() != metadata_hashed {
-            return Err(anyhow!(
-                "Lnurl: description hash not matching metadata! {} != {}",
-                metadata_hashed,
-                invoice_decoded.description_hash.unwrap()
-            ));
+        // The record of an invoice handed out again keeps its state
+        if let Err(e) = save_lnurl_record(
+            plugin.clone(),
+            &payment_hash,
+            &LnurlInvoiceRecord {
+                invoice: callback_response.pr.clone(),
+                success_action: success_action.clone(),
+                verify: callback_response.verify.clone(),
+                settled: false,
+                disposable,
+            },
+            None,
+        )
+        .await
+        {
+            if load_lnurl_record(plugin.clone(), &payment_hash)
+                .await?
+                .is_none()
+            {
+                return Err(e);
+            }
+            log::debug!("Lnurl: invoice {payment_hash} is already stored, keeping its record");
         }
     }
 
     params.remove("amount_msat");
     *params.get_mut(invstring_name).unwrap() = serde_json::Value::String(callback_response.pr);
-    Ok(())
+    Ok(Resolution {
+        success_action,
+        verify: callback_response.verify,
+        metadata: Some(lnurlp_config.parsed_metadata),
+        from_lnurl: true,
+        address_method: None,
+        bip353: None,
+    })
+}
+
+pub async fn fetch_lnurl_json(
+    client: &reqwest::Client,
+    url: &str,
+    what: &str,
+) -> Result<serde_json::Value, Error> {
+    let response_raw = client.get(url).send().await?;
+    let status = response_raw.status();
+    let body = response_raw.text().await?;
+    parse_lnurl_response(what, status, &body)
+}
+
+// Services answer with a LUD-06 error envelope on failure, sometimes with a
+// non-2xx status, and their reason must not get lost
+pub fn parse_lnurl_response(
+    what: &str,
+    status: reqwest::StatusCode,
+    body: &str,
+) -> Result<serde_json::Value, Error> {
+    let response = serde_json::from_str::<serde_json::Value>(body).ok();
+    let error_reason = response.as_ref().and_then(|r| {
+        if r.get("status")
+            .and_then(|s| s.as_str())
+            .is_some_and(|s| s.eq_ignore_ascii_case("ERROR"))
+        {
+            Some(
+                r.get("reason")
+                    .and_then(|r| r.as_str())
+                    .map(ToOwned::to_owned),
+            )
+        } else {
+            None
+        }
+    });
+    if !status.is_success() {
+        return Err(LnurlServiceError {
+            what: what.to_owned(),
+            http_status: Some(status.as_u16()),
+            reason: error_reason.flatten(),
+        }
+        .into());
+    }
+    if let Some(reason) = error_reason {
+        return Err(LnurlServiceError {
+            what: what.to_owned(),
+            http_status: None,
+            reason,
+        }
+        .into());
+    }
+    response.ok_or_else(|| anyhow!("Not a valid LNURL {what} response: {body}"))
+}
+
+// The structured LNURL error of the service, if any, for the `data` of rpc errors
+pub fn lnurl_error_data(error: &Error) -> Option<serde_json::Value> {
+    error.chain().find_map(|e| {
+        if let Some(service_error) = e.downcast_ref::<LnurlServiceError>() {
+            Some(service_error.to_json())
+        } else {
+            e.downcast_ref::<AddressResolutionError>()
+                .map(AddressResolutionError::to_json)
+        }
+    })
+}
+
+pub async fn fetch_lnurl_verify(config: &Config, verify_url: &str) -> Result<LnurlVerify, Error> {
+    let client = build_client(config)?;
+    let verify_response = fetch_lnurl_json(&client, verify_url, "verify").await?;
+    serde_json::from_value(verify_response).context("Not a valid LNURL verify response")
 }
 
 fn validate_lnurl_config(
@@ -161,40 +514,12 @@ fn validate_lnurl_config(
         ));
     }
     if let Some(lnaddr) = lnaddress {
-        let metadata_json: serde_json::Value = serde_json::from_str(&lnurl_config.metadata)?;
-        let mut lnaddress_found = false;
-        let serde_json::Value::Array(metadata_outer_array) = metadata_json else {
-            return Err(anyhow!("metadata not an array!: {}", lnurl_config.metadata));
-        };
-
-        for meta in metadata_outer_array {
-            let serde_json::Value::Array(metadata_inner_array) = meta else {
-                return Err(anyhow!("inner metadata not an array!: {}", &meta));
-            };
-            if metadata_inner_array.len() != 2 {
-                return Err(anyhow!(
-                    "inner metadata array is not of length 2!: {metadata_inner_array:?}"
-                ));
-            }
-            let data_type = metadata_inner_array
-                .first()
-                .unwrap()
-                .as_str()
-                .ok_or(anyhow!("inner metadata identifier is not a string:"))?;
-            if !data_type.eq_ignore_ascii_case("text/identifier")
-                && !data_type.eq_ignore_ascii_case("text/email")
-            {
-                continue;
-            }
-            let data = metadata_inner_array
-                .get(1)
-                .unwrap()
-                .as_str()
-                .ok_or(anyhow!("inner metadata content is not a string:"))?;
-            if data.eq_ignore_ascii_case(lnaddr) {
-                lnaddress_found = true;
-            }
-        }
+        let metadata = &lnurl_config.parsed_metadata;
+        let lnaddress_found = metadata
+            .identifier
+            .iter()
+            .chain(metadata.email.iter())
+            .any(|data| data.eq_ignore_ascii_case(lnaddr));
 
         // Quite a few servers in the wild are not including the text/identifier or text/email data..
         if !lnaddress_found {
@@ -214,32 +539,398 @@ fn validate_lnurl_config(
     Ok(())
 }
 
+// LUD-01: plain bech32 with the `lnurl` hrp, but LNURLs are often longer than
+// the 90 characters of BIP-173 or even bech32's code length of 1023
+enum LnurlBech32 {}
+impl Checksum for LnurlBech32 {
+    type MidstateRepr = u32;
+    const CODE_LENGTH: usize = LNURL_MAX_LENGTH;
+    const CHECKSUM_LENGTH: usize = Bech32::CHECKSUM_LENGTH;
+    const GENERATOR_SH: [u32; 5] = Bech32::GENERATOR_SH;
+    const TARGET_RESIDUE: u32 = Bech32::TARGET_RESIDUE;
+}
+
+pub fn decode_lnurl(invstring: &str) -> Result<String, Error> {
+    let unchecked = UncheckedHrpstring::new(invstring)
+        .map_err(|e| anyhow!("LNURL: not a valid bech32 string: {e}"))?;
+    let hrp = unchecked.hrp();
+    if !hrp.as_str().eq_ignore_ascii_case("lnurl") {
+        return Err(anyhow!("LNURL: bech32 prefix must be `lnurl`, got `{hrp}`"));
+    }
+    let checked = match unchecked.validate_and_remove_checksum::<LnurlBech32>() {
+        Ok(c) => c,
+        Err(ChecksumError::CodeLength(e)) => {
+            return Err(anyhow!(
+                "LNURL: too long, {} characters is more than the supported {}",
+                e.encoded_length,
+                e.code_length
+            ));
+        }
+        Err(e) => return Err(anyhow!("LNURL: invalid bech32 checksum: {e}")),
+    };
+    let config_url = String::from_utf8(checked.byte_iter().collect())
+        .map_err(|e| anyhow!("LNURL: decoded url is not utf-8: {e}"))?;
+    log::debug!("lnurl hrp:{hrp} url:{config_url}");
+    check_lnurl_url(&config_url)?;
+    Ok(config_url)
+}
+
+// LUD-01: https only, http is allowed for onion services
+fn check_lnurl_url(lnurl_url: &str) -> Result<(), Error> {
+    let url = url::Url::parse(lnurl_url)
+        .map_err(|e| anyhow!("LNURL: decoded string is not a url: {e}: {lnurl_url}"))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("LNURL: no domain in url: {lnurl_url}"))?;
+    match url.scheme() {
+        "https" => Ok(()),
+        // LUD-01 only allows http for onion services, local hosts are for testing
+        "http" if host.ends_with(".onion") || host == "localhost" || host == "127.0.0.1" => Ok(()),
+        scheme => Err(anyhow!(
+            "LNURL: url scheme must be https (or http for onion services), got `{scheme}`: \
+            {lnurl_url}"
+        )),
+    }
+}
+
+// LUD-01: `https://site.com/?lightning=LNURL1...` fallback urls, e.g. from QR codes
+pub fn lnurl_fallback_param(invstring: &str) -> Option<String> {
+    if strip_prefix_ignore_ascii_case(invstring, "https://").is_none()
+        && strip_prefix_ignore_ascii_case(invstring, "http://").is_none()
+    {
+        return None;
+    }
+    url::Url::parse(invstring)
+        .ok()?
+        .query_pairs()
+        .find(|(key, _)| key.eq_ignore_ascii_case("lightning"))
+        .map(|(_, value)| value.trim().to_owned())
+        .filter(|value| strip_prefix_ignore_ascii_case(value, "lnurl").is_some())
+}
+
+pub fn unpayable_tag_error(tag: LnurlTag, lnurl: &str) -> Error {
+    anyhow!(
+        "lnurl: detected {}, {tag} can not be paid, use `{}`: {lnurl}",
+        tag.protocol(),
+        tag.rpc_method()
+    )
+}
+
+// LUD-04 login urls carry their tag in the query instead of a json response
+pub fn url_query_tag(url: &str) -> Option<LnurlTag> {
+    url::Url::parse(url)
+        .ok()?
+        .query_pairs()
+        .find(|(key, _)| key == "tag")
+        .and_then(|(_, value)| value.parse::<LnurlTag>().ok())
+}
+
+// Accepts bech32 LNURLs, LUD-01 fallback urls and LUD-17 urls, optionally prefixed with a URI scheme
+pub fn lnurl_to_url(lnurl: &str) -> Result<(String, Option<LnurlTag>), Error> {
+    let mut lnurl_stripped = lnurl.trim();
+    for uri_scheme in URI_SCHEMES {
+        if let Some(stripped) = strip_prefix_ignore_ascii_case(lnurl_stripped, uri_scheme) {
+            lnurl_stripped = stripped;
+            break;
+        }
+    }
+    if let Some((url, tag)) = lud17_to_url(lnurl_stripped)? {
+        return Ok((url, Some(tag)));
+    }
+    if let Some(fallback) = lnurl_fallback_param(lnurl_stripped) {
+        return Ok((decode_lnurl(&fallback.to_lowercase())?, None));
+    }
+    Ok((decode_lnurl(&lnurl_stripped.to_lowercase())?, None))
+}
+
+// LUD-17: replace the protocol scheme with https, or http for onion services
+pub fn lud17_to_url(invstring: &str) -> Result<Option<(String, LnurlTag)>, Error> {
+    for (scheme, tag) in LUD17_SCHEMES {
+        let Some(rest) = strip_prefix_ignore_ascii_case(invstring, scheme) else {
+            continue;
+        };
+        let https_url = url::Url::parse(&format!("https://{rest}"))
+            .map_err(|e| anyhow!("LNURL: invalid {scheme} url: {e}"))?;
+        let host = https_url
+            .host_str()
+            .ok_or_else(|| anyhow!("LNURL: no domain in url: {invstring}"))?;
+        return Ok(Some((
+            format!("{}://{rest}", service_url_scheme(host)),
+            tag,
+        )));
+    }
+    Ok(None)
+}
+
+pub fn service_url_scheme(host: &str) -> &'static str {
+    if host.ends_with(".onion") || host.contains("localhost") || host.contains("127.0.0.1") {
+        "http"
+    } else {
+        "https"
+    }
+}
+
 pub async fn resolve_lnurl(
     plugin: Plugin<PluginState>,
     invstring_name: &str,
-    invstring: &str,
+    config_url: String,
     lnaddress: Option<&str>,
-    amount_msat: Amount,
-    message: Option<String>,
+    amount_msat: Option<Amount>,
+    payer: PayerInfo,
     params: &mut Map<String, serde_json::Value>,
-) -> Result<(), Error> {
-    let (hrp, config_url_bytes) = bech32::decode(invstring)?;
-    let config_url = String::from_utf8(config_url_bytes)?;
-    log::debug!("lnurl hrp:{hrp} url:{config_url}");
-
+) -> Result<Resolution, Error> {
     let config = plugin.state().config.lock().clone();
 
-    let (lnurlp_callback, lnurlp_config) =
-        try_fetch_lnurl(&config, lnaddress, config_url, amount_msat, message).await?;
+    let (lnurlp_callback, lnurlp_config, committed_description, amount_msat) = try_fetch_lnurl(
+        plugin.clone(),
+        &config,
+        lnaddress,
+        config_url,
+        amount_msat,
+        payer,
+    )
+    .await?;
 
     process_lnurl_invoice(
         plugin,
         invstring_name,
         lnurlp_callback,
         lnurlp_config,
+        committed_description,
         amount_msat,
         &config,
         params,
     )
     .await
 }
+
+#[test]
+fn test_lud17_to_url() {
+    assert_eq!(
+        lud17_to_url("lnurlp://site.com/lnurl-pay?session=Ab1").unwrap(),
+        Some((
+            "https://site.com/lnurl-pay?session=Ab1".to_owned(),
+            LnurlTag::PayRequest
+        ))
+    );
+    assert_eq!(
+        lud17_to_url("LNURLW://abcdef.onion/withdraw").unwrap(),
+        Some((
+            "http://abcdef.onion/withdraw".to_owned(),
+            LnurlTag::WithdrawRequest
+        ))
+    );
+    assert_eq!(
+        lud17_to_url("keyauth://site.com/login?tag=login&k1=00").unwrap(),
+        Some((
+            "https://site.com/login?tag=login&k1=00".to_owned(),
+            LnurlTag::Login
+        ))
+    );
+    assert_eq!(lud17_to_url("lnurl1dp68gurn8ghj7").unwrap(), None);
+    assert!(lud17_to_url("lnurlc://").is_err());
+}
+
+#[test]
+fn test_url_query_tag() {
+    assert_eq!(
+        url_query_tag("https://site.com/auth?tag=login&k1=00"),
+        Some(LnurlTag::Login)
+    );
+    assert_eq!(
+        url_query_tag("https://site.com/lnurl?TAG=login&k1=00"),
+        None
+    );
+    assert_eq!(
+        url_query_tag("https://site.com/c?tag=CHANNELREQUEST"),
+        Some(LnurlTag::ChannelRequest)
+    );
+    assert_eq!(url_query_tag("https://site.com/.well-known/lnurlp/x"), None);
+    assert_eq!(url_query_tag("https://site.com/c?tag=unknown"), None);
+}
+
+#[test]
+fn test_parse_callback_url() {
+    let mut callback_url = parse_callback_url("https://site.com/cb?session=ab%2Bc&x=1").unwrap();
+    callback_url
+        .query_pairs_mut()
+        .append_pair("amount", "1000")
+        .append_pair("comment", "tea & cake #1 \u{2615}");
+    assert_eq!(
+        callback_url.as_str(),
+        "https://site.com/cb?session=ab%2Bc&x=1&amount=1000&comment=tea+%26+cake+%231+%E2%98%95"
+    );
+    let pairs: Vec<(String, String)> = callback_url.query_pairs().into_owned().collect();
+    assert_eq!(pairs[0], ("session".to_owned(), "ab+c".to_owned()));
+    assert_eq!(
+        pairs[3],
+        ("comment".to_owned(), "tea & cake #1 \u{2615}".to_owned())
+    );
+
+    for callback in ["https://site.com/cb", "https://site.com/cb?"] {
+        let mut callback_url = parse_callback_url(callback).unwrap();
+        callback_url.query_pairs_mut().append_pair("amount", "1000");
+        assert_eq!(callback_url.as_str(), "https://site.com/cb?amount=1000");
+    }
+    assert!(parse_callback_url("not a url").is_err());
+}
+
+#[test]
+fn test_parse_lnurl_response() {
+    let ok = parse_lnurl_response(
+        "invoice",
+        reqwest::StatusCode::OK,
+        r#"{"pr":"lnbc1","routes":[]}"#,
+    )
+    .unwrap();
+    assert_eq!(ok.get("pr").unwrap(), "lnbc1");
+
+    let err = parse_lnurl_response(
+        "lnurl config",
+        reqwest::StatusCode::OK,
+        r#"{"status":"ERROR","reason":"User does not exist"}"#,
+    )
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "LNURL: lnurl config returned an error: User does not exist"
+    );
+    assert_eq!(
+        lnurl_error_data(&err).unwrap(),
+        serde_json::json!({"lnurl_request":"lnurl config","reason":"User does not exist"})
+    );
+
+    let err = parse_lnurl_response(
+        "invoice",
+        reqwest::StatusCode::BAD_REQUEST,
+        r#"{"status":"error","reason":"Amount too small"}"#,
+    )
+    .unwrap_err()
+    .context("wrapped");
+    assert_eq!(
+        lnurl_error_data(&err).unwrap(),
+        serde_json::json!({
+            "lnurl_request":"invoice",
+            "http_status":400,
+            "reason":"Amount too small"
+        })
+    );
+
+    let err = parse_lnurl_response(
+        "invoice",
+        reqwest::StatusCode::SERVICE_UNAVAILABLE,
+        "<html>down</html>",
+    )
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "LNURL: got bad status for invoice: 503: no reason given"
+    );
+
+    let err = parse_lnurl_response("invoice", reqwest::StatusCode::OK, "nope").unwrap_err();
+    assert!(lnurl_error_data(&err).is_none());
+}
+
+#[test]
+fn test_decode_lnurl() {
+    LnurlBech32::sanity_check();
+
+    let encode = |hrp: &str, url: &str| {
+        bech32::encode::<Bech32>(bech32::Hrp::parse(hrp).unwrap(), url.as_bytes()).unwrap()
+    };
+    let lnurl = encode("lnurl", "https://site.com/lnurlp/alice");
+    assert_eq!(
+        decode_lnurl(&lnurl).unwrap(),
+        "https://site.com/lnurlp/alice"
+    );
+    assert_eq!(
+        decode_lnurl(&encode("lnurl", "http://abcdef.onion/lnurlp")).unwrap(),
+        "http://abcdef.onion/lnurlp"
+    );
+    assert!(
+        decode_lnurl(&encode("lnbc", "https://site.com/lnurlp/alice"))
+            .unwrap_err()
+            .to_string()
+            .contains("bech32 prefix must be `lnurl`")
+    );
+    assert!(
+        decode_lnurl(&encode("lnurl", "http://site.com/lnurlp/alice"))
+            .unwrap_err()
+            .to_string()
+            .contains("url scheme must be https")
+    );
+    assert!(decode_lnurl(&encode("lnurl", "ftp://site.com/x")).is_err());
+    let bech32m = bech32::encode::<bech32::Bech32m>(
+        bech32::Hrp::parse("lnurl").unwrap(),
+        b"https://site.com/lnurlp/alice",
+    )
+    .unwrap();
+    assert!(decode_lnurl(&bech32m).is_err());
+
+    let long_url = format!("https://site.com/lnurlp?k1={}", "ab".repeat(600));
+    let long_lnurl =
+        bech32::encode::<LnurlBech32>(bech32::Hrp::parse("lnurl").unwrap(), long_url.as_bytes())
+            .unwrap();
+    assert!(long_lnurl.len() > Bech32::CODE_LENGTH);
+    assert_eq!(decode_lnurl(&long_lnurl).unwrap(), long_url);
+
+    assert_eq!(
+        lnurl_fallback_param(&format!(
+            "https://site.com/?lightning={}",
+            lnurl.to_uppercase()
+        )),
+        Some(lnurl.to_uppercase())
+    );
+    assert_eq!(
+        lnurl_fallback_param("https://site.com/?lightning=lnbc1"),
+        None
+    );
+    assert_eq!(lnurl_fallback_param(&lnurl), None);
+}
+
+#[test]
+fn test_lnurlp_amount() {
+    let mut lnurlp_config: LnurlpConfig = serde_json::from_value(json!({
+        "callback": "https://site.com/cb",
+        "minSendable": 10_000,
+        "maxSendable": 10_000,
+        "metadata": "[]",
+        "tag": "payRequest",
+    }))
+    .unwrap();
+    assert_eq!(lnurlp_amount(&lnurlp_config, None).unwrap().msat(), 10_000);
+    assert_eq!(
+        lnurlp_amount(&lnurlp_config, Some(Amount::from_msat(5_000)))
+            .unwrap()
+            .msat(),
+        5_000
+    );
+    lnurlp_config.max_sendable = 20_000;
+    assert_eq!(
+        lnurlp_amount(&lnurlp_config, None).unwrap_err().to_string(),
+        "lnurl: missing amount_msat, service accepts 10000msat to 20000msat"
+    );
+}
+
+#[test]
+fn test_check_lnurl_url() {
+    assert!(check_lnurl_url("https://site.com/lnurlp/alice").is_ok());
+    assert!(check_lnurl_url("http://site.onion/lnurlp/alice").is_ok());
+    assert!(check_lnurl_url("http://127.0.0.1:8080/lnurlp/alice").is_ok());
+    assert!(check_lnurl_url("http://localhost/lnurlp/alice").is_ok());
+    for url in [
+        "http://site.com/lnurlp/alice",
+        "http://localhost.example.com/lnurlp/alice",
+        "http://127.0.0.1.example.com/lnurlp/alice",
+        "http://site.onion.example.com/lnurlp/alice",
+        "ftp://site.com/lnurlp/alice",
+    ] {
+        assert!(
+            check_lnurl_url(url)
+                .unwrap_err()
+                .to_string()
+                .contains("url scheme must be https"),
+            "{url}"
+        );
+    }
+}
@@ -6,33 +6,68 @@ use cln_plugin::{
     HookBuilder,
     HookFilter,
     RpcMethodBuilder,
-    options::{DefaultBooleanConfigOption, IntegerConfigOption, StringConfigOption},
+    messages::NotificationTopic,
+    options::{
+        DefaultBooleanConfigOption,
+        DefaultIntegerConfigOption,
+        DefaultStringConfigOption,
+        IntegerConfigOption,
+        StringConfigOption,
+    },
 };
 use cln_rpc::{
     ClnRpc,
     model::requests::{GetinfoRequest, ListconfigsRequest},
 };
-use hooks::hook_handler;
+use hooks::{hook_handler, onion_message_handler, sendpay_success_handler};
 use parse::{get_startup_options, parse_pay_args, setconfig_callback};
-use rpc::payany;
+use rpc::{payany, payany_auth, payany_channel, payany_verify, payany_withdraw};
 use structs::PluginState;
 use util::check_handle_option;
 
 use crate::util::at_or_above_version;
 
+mod auth;
+mod bip353;
+mod blip32;
 mod budget;
+mod channel;
+mod datastore;
+mod fallback;
 mod fetch;
 mod hooks;
+mod idempotency;
+mod invoice;
+mod keys;
+mod lenient;
 mod lnurl;
+mod metadata;
+mod nostr;
 mod parse;
+mod refetch;
 mod rpc;
 mod structs;
+mod successaction;
 mod util;
+mod withdraw;
 
 const OPT_PAYANY_BUDGET_PER: &str = "payany-budget-per";
 const OPT_PAYANY_BUDGET_AMOUNT_MSAT: &str = "payany-budget-amount-msat";
 const OPT_PAYANY_HANDLE_PAY: &str = "payany-xpay-handle-pay";
 const OPT_PAYANY_STRICT_LNURL: &str = "payany-strict-lnurl";
+const OPT_PAYANY_PAYER_NAME: &str = "payany-payer-name";
+const OPT_PAYANY_PAYER_IDENTIFIER: &str = "payany-payer-identifier";
+const OPT_PAYANY_NOSTR_KEY: &str = "payany-nostr-key";
+const OPT_PAYANY_INVOICE_EXPIRY_MARGIN: &str = "payany-invoice-expiry-margin";
+const OPT_PAYANY_LNURL_REFETCH: &str = "payany-lnurl-refetch";
+const OPT_PAYANY_ADDRESS_PRIORITY: &str = "payany-address-priority";
+const OPT_PAYANY_ADDRESS_FALLBACK: &str = "payany-address-fallback";
+const OPT_PAYANY_DNS_RESOLVERS: &str = "payany-dns-resolvers";
+const OPT_PAYANY_DNS_RESOLVER_TIMEOUT: &str = "payany-dns-resolver-timeout";
+const OPT_PAYANY_ADDRESS_METHOD_GRACE: &str = "payany-address-method-grace";
+const OPT_PAYANY_DOH_SERVERS: &str = "payany-doh-servers";
+
+const NOTIFICATION_SUCCESS_ACTION: &str = "payany_success_action";
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), anyhow::Error> {
@@ -64,16 +99,126 @@ async fn main() -> Result<(), anyhow::Error> {
         "payany adheres strictly to lud-06 and lud-16",
     )
     .dynamic();
+    let opt_payany_payer_name = StringConfigOption::new_str_no_default(
+        OPT_PAYANY_PAYER_NAME,
+        "name sent to lnurl services requesting payerData (LUD-18)",
+    )
+    .dynamic();
+    let opt_payany_payer_identifier = StringConfigOption::new_str_no_default(
+        OPT_PAYANY_PAYER_IDENTIFIER,
+        "identifier sent to lnurl services requesting payerData (LUD-18)",
+    )
+    .dynamic();
+
+    let opt_payany_nostr_key = StringConfigOption::new_str_no_default(
+        OPT_PAYANY_NOSTR_KEY,
+        "nostr secret key (hex or nsec) to sign zap requests with (NIP-57)",
+    )
+    .dynamic();
+
+    let opt_payany_invoice_expiry_margin = DefaultIntegerConfigOption::new_i64_with_default(
+        OPT_PAYANY_INVOICE_EXPIRY_MARGIN,
+        60,
+        "seconds an lnurl invoice must at least be valid for",
+    )
+    .dynamic();
+
+    let opt_payany_lnurl_refetch = DefaultIntegerConfigOption::new_i64_with_default(
+        OPT_PAYANY_LNURL_REFETCH,
+        0,
+        "times payany pays lnurl invoices itself and fetches a new one if it expired or was \
+        rejected, 0 to disable",
+    )
+    .dynamic();
+
+    let opt_payany_address_priority = DefaultStringConfigOption::new_str_with_default(
+        OPT_PAYANY_ADDRESS_PRIORITY,
+        "lnurl,bip353",
+        "comma separated order of methods to resolve user@domain addresses with: lnurl, bip353, \
+        blip32",
+    )
+    .dynamic();
+
+    let opt_payany_address_fallback = DefaultBooleanConfigOption::new_bool_with_default(
+        OPT_PAYANY_ADDRESS_FALLBACK,
+        false,
+        "payany pays lightning addresses itself and tries the next resolution method if the \
+        payment fails",
+    )
+    .dynamic();
+
+    let opt_payany_dns_resolvers = StringConfigOption::new_str_no_default(
+        OPT_PAYANY_DNS_RESOLVERS,
+        "comma separated node ids of bLIP-32 DNS resolvers, found in gossip if unset",
+    )
+    .dynamic();
+
+    let opt_payany_dns_resolver_timeout = DefaultIntegerConfigOption::new_i64_with_default(
+        OPT_PAYANY_DNS_RESOLVER_TIMEOUT,
+        30,
+        "seconds a bLIP-32 DNS resolver has to answer a query",
+    )
+    .dynamic();
+
+    let opt_payany_address_method_grace = DefaultIntegerConfigOption::new_i64_with_default(
+        OPT_PAYANY_ADDRESS_METHOD_GRACE,
+        3,
+        "seconds a higher priority address method may still take after a lower priority one \
+        resolved the address",
+    )
+    .dynamic();
+
+    let opt_payany_doh_servers = DefaultStringConfigOption::new_str_with_default(
+        OPT_PAYANY_DOH_SERVERS,
+        "https://dns.google/dns-query,https://cloudflare-dns.com/dns-query,\
+        https://dns.quad9.net/dns-query",
+        "comma separated DNS-over-HTTPS servers asked in order for bip353 proofs",
+    )
+    .dynamic();
 
     let confplugin = match Builder::new(tokio::io::stdin(), tokio::io::stdout())
         .option(opt_payany_budget_per)
         .option(opt_payany_budget_amount_msat)
         .option(opt_payany_handle_pay)
         .option(opt_payany_strict_lnurl)
+        .option(opt_payany_payer_name)
+        .option(opt_payany_payer_identifier)
+        .option(opt_payany_nostr_key)
+        .option(opt_payany_invoice_expiry_margin)
+        .option(opt_payany_lnurl_refetch)
+        .option(opt_payany_address_priority)
+        .option(opt_payany_address_fallback)
+        .option(opt_payany_dns_resolvers)
+        .option(opt_payany_dns_resolver_timeout)
+        .option(opt_payany_address_method_grace)
+        .option(opt_payany_doh_servers)
         .rpcmethod_from_builder(
             RpcMethodBuilder::new("payany", payany)
                 .description("fetch invoice for static ln payment method")
-                .usage("invstring amount_msat [message]"),
+                .usage(
+                    "invstring amount_msat [message] [payer_name] [payer_identifier] \
+                    [zap_pubkey] [zap_event] [zap_relays] [zap_content] [quote] [quote_id] [idempotency_key]",
+                ),
+        )
+        .rpcmethod_from_builder(
+            RpcMethodBuilder::new("payany-verify", payany_verify)
+                .description("check the LUD-21 verify url of a fetched LNURL invoice")
+                .usage("payment_hash"),
+        )
+        .rpcmethod_from_builder(
+            RpcMethodBuilder::new("payany-withdraw", payany_withdraw)
+                .description("redeem a LNURL-withdraw (LUD-03) to an invoice of this node")
+                .usage("lnurl [amount_msat] [label]"),
+        )
+        .rpcmethod_from_builder(
+            RpcMethodBuilder::new("payany-auth", payany_auth)
+                .description("log in to a LNURL-auth (LUD-04) service with a node-derived key")
+                .usage("lnurl"),
+        )
+        .rpcmethod_from_builder(
+            RpcMethodBuilder::new("payany-channel", payany_channel)
+                .description("request an incoming channel from a LNURL-channel (LUD-02) service")
+                .usage("lnurl [private]"),
         )
         .hook_from_builder(HookBuilder::new("rpc_command", hook_handler).filters(vec![
             HookFilter::Str("xpay".to_owned()),
@@ -81,6 +226,12 @@ async fn main() -> Result<(), anyhow::Error> {
             HookFilter::Str("renepay".to_owned()),
             HookFilter::Str("setconfig".to_owned()),
         ]))
+        .hook_from_builder(HookBuilder::new(
+            "onion_message_recv_secret",
+            onion_message_handler,
+        ))
+        .subscribe("sendpay_success", sendpay_success_handler)
+        .notification(NotificationTopic::new(NOTIFICATION_SUCCESS_ACTION))
         .setconfig_callback(setconfig_callback)
         .dynamic()
         .configure()
@@ -106,7 +257,7 @@ async fn main() -> Result<(), anyhow::Error> {
                 )
                 .await?;
 
-                let cln_version = rpc.call_typed(&GetinfoRequest {}).await?.version;
+                let getinfo = rpc.call_typed(&GetinfoRequest {}).await?;
 
                 let listconfigs = rpc
                     .call_typed(&ListconfigsRequest { config: None })
@@ -115,7 +266,8 @@ async fn main() -> Result<(), anyhow::Error> {
                     .ok_or_else(|| anyhow!("No `configs` found in listconfigs response"))?;
 
                 let mut config = plugin.state().config.lock();
-                config.version = cln_version;
+                config.version = getinfo.version;
+                config.network = getinfo.network;
 
                 config.tor_proxy = if let Some(proxy_config) = listconfigs.proxy {
                     if let Some(always_use_proxy_config) = listconfigs.always_use_proxy {
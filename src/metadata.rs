@@ -0,0 +1,177 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde_json::json;
+
+use crate::{
+    structs::{LnurlMetadata, MetadataImage},
+    util::check_strict,
+};
+
+// LUD-06 requires exactly one text/plain entry and allows one long description
+// and one image, LUD-16 adds text/identifier and text/email, LUD-20 text/long-desc
+pub fn parse_metadata(metadata: &str, strict_lnurl: bool) -> Result<LnurlMetadata, anyhow::Error> {
+    // The raw metadata is still committed to by the description hash
+    let metadata_json: serde_json::Value = match serde_json::from_str(metadata) {
+        Ok(o) => o,
+        Err(e) => {
+            check_strict(
+                strict_lnurl,
+                &format!("metadata is not valid json: {e}: {metadata}"),
+            )?;
+            return Ok(LnurlMetadata::default());
+        }
+    };
+    let serde_json::Value::Array(metadata_outer_array) = metadata_json else {
+        check_strict(strict_lnurl, &format!("metadata not an array: {metadata}"))?;
+        return Ok(LnurlMetadata::default());
+    };
+
+    let mut parsed = LnurlMetadata::default();
+    for meta in metadata_outer_array {
+        let serde_json::Value::Array(metadata_inner_array) = meta else {
+            check_strict(
+                strict_lnurl,
+                &format!("inner metadata not an array: {meta}"),
+            )?;
+            continue;
+        };
+        let [data_type, data] = metadata_inner_array.as_slice() else {
+            check_strict(
+                strict_lnurl,
+                &format!("inner metadata array is not of length 2: {metadata_inner_array:?}"),
+            )?;
+            continue;
+        };
+        let Some(data_type) = data_type.as_str() else {
+            check_strict(
+                strict_lnurl,
+                &format!("inner metadata identifier is not a string: {data_type}"),
+            )?;
+            continue;
+        };
+        let Some(data) = data.as_str() else {
+            check_strict(
+                strict_lnurl,
+                &format!("inner metadata content is not a string: {data}"),
+            )?;
+            continue;
+        };
+        let data_type = data_type.to_lowercase();
+        let data = data.to_owned();
+
+        let slot = match data_type.as_str() {
+            "text/plain" => &mut parsed.description,
+            "text/long-desc" => &mut parsed.long_description,
+            "text/identifier" => &mut parsed.identifier,
+            "text/email" => &mut parsed.email,
+            "image/png;base64" | "image/jpeg;base64" => {
+                if parsed.image.is_some() {
+                    check_strict(strict_lnurl, "more than one image in metadata")?;
+                    continue;
+                }
+                if let Err(e) = STANDARD.decode(&data) {
+                    check_strict(strict_lnurl, &format!("metadata image is not base64: {e}"))?;
+                    continue;
+                }
+                parsed.image = Some(MetadataImage {
+                    mime_type: data_type.trim_end_matches(";base64").to_owned(),
+                    data,
+                });
+                continue;
+            }
+            _ => {
+                log::debug!("ignoring unknown metadata entry: {data_type}");
+                continue;
+            }
+        };
+        if slot.is_some() {
+            check_strict(
+                strict_lnurl,
+                &format!("more than one {data_type} in metadata"),
+            )?;
+            continue;
+        }
+        *slot = Some(data);
+    }
+
+    if parsed.description.is_none() {
+        check_strict(strict_lnurl, "metadata is missing text/plain")?;
+    }
+    Ok(parsed)
+}
+
+pub fn metadata_to_json(metadata: &LnurlMetadata) -> serde_json::Value {
+    let mut result = json!({});
+    let result_obj = result.as_object_mut().unwrap();
+    if let Some(description) = &metadata.description {
+        result_obj.insert("description".to_owned(), json!(description));
+    }
+    if let Some(long_description) = &metadata.long_description {
+        result_obj.insert("long_description".to_owned(), json!(long_description));
+    }
+    if let Some(image) = &metadata.image {
+        result_obj.insert(
+            "image".to_owned(),
+            json!({"mime_type":image.mime_type,"base64":image.data}),
+        );
+    }
+    if let Some(identifier) = &metadata.identifier {
+        result_obj.insert("identifier".to_owned(), json!(identifier));
+    }
+    if let Some(email) = &metadata.email {
+        result_obj.insert("email".to_owned(), json!(email));
+    }
+    result
+}
+
+#[test]
+fn test_parse_metadata() {
+    let metadata = json!([
+        ["text/plain", "coffee"],
+        ["text/long-desc", "a very good coffee"],
+        ["image/png;base64", STANDARD.encode([137u8, 80, 78, 71])],
+        ["text/identifier", "alice@site.com"],
+        ["application/x-unknown", "ignored"]
+    ])
+    .to_string();
+    let parsed = parse_metadata(&metadata, true).unwrap();
+    assert_eq!(parsed.description.as_deref(), Some("coffee"));
+    assert_eq!(
+        parsed.long_description.as_deref(),
+        Some("a very good coffee")
+    );
+    assert_eq!(parsed.image.as_ref().unwrap().mime_type, "image/png");
+    assert_eq!(parsed.identifier.as_deref(), Some("alice@site.com"));
+    assert_eq!(parsed.email, None);
+
+    let no_plain = json!([["text/long-desc", "x"]]).to_string();
+    assert!(parse_metadata(&no_plain, true).is_err());
+    assert_eq!(parse_metadata(&no_plain, false).unwrap().description, None);
+
+    let bad_image = json!([["text/plain", "x"], ["image/jpeg;base64", "!!"]]).to_string();
+    assert!(parse_metadata(&bad_image, true).is_err());
+    assert!(parse_metadata(&bad_image, false).unwrap().image.is_none());
+
+    assert!(parse_metadata("{}", true).is_err());
+    assert_eq!(parse_metadata("{}", false).unwrap().description, None);
+    assert!(parse_metadata("[[\"text/plain\"", true).is_err());
+    assert_eq!(
+        parse_metadata("[[\"text/plain\"", false)
+            .unwrap()
+            .description,
+        None
+    );
+
+    let malformed = json!([
+        ["text/plain"],
+        "text/plain",
+        [1, "x"],
+        ["text/long-desc", 2],
+        ["text/plain", "x", "y"],
+        ["text/plain", "coffee"]
+    ])
+    .to_string();
+    assert!(parse_metadata(&malformed, true).is_err());
+    let parsed = parse_metadata(&malformed, false).unwrap();
+    assert_eq!(parsed.description.as_deref(), Some("coffee"));
+    assert_eq!(parsed.long_description, None);
+}
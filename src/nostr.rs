@@ -0,0 +1,151 @@
+use anyhow::anyhow;
+use bech32::{Bech32, Hrp};
+use bitcoin::{
+    hashes::{Hash, sha256},
+    hex::{DisplayHex, FromHex},
+    secp256k1::{Keypair, Message, Secp256k1, SecretKey, XOnlyPublicKey},
+};
+use serde_json::json;
+
+use crate::structs::ZapInfo;
+
+const ZAP_REQUEST_KIND: u64 = 9734;
+
+// Accepts the secret key as 64 hex characters or as a NIP-19 `nsec`
+pub fn parse_nostr_key(key: &str) -> Result<Keypair, anyhow::Error> {
+    let key = key.trim();
+    let secret_bytes = if key.to_lowercase().starts_with("nsec1") {
+        let (hrp, data) =
+            bech32::decode(key).map_err(|e| anyhow!("nostr key is not a valid nsec: {e}"))?;
+        if hrp.as_str() != "nsec" {
+            return Err(anyhow!("nostr key has wrong prefix: {hrp}"));
+        }
+        data
+    } else {
+        Vec::<u8>::from_hex(key).map_err(|e| anyhow!("nostr key is not valid hex: {e}"))?
+    };
+    let secret_key = SecretKey::from_slice(&secret_bytes)
+        .map_err(|e| anyhow!("nostr key is not a valid secret key: {e}"))?;
+    Ok(Keypair::from_secret_key(&Secp256k1::new(), &secret_key))
+}
+
+pub fn parse_nostr_pubkey(pubkey: &str) -> Result<XOnlyPublicKey, anyhow::Error> {
+    let pubkey = pubkey.trim();
+    if pubkey.to_lowercase().starts_with("npub1") {
+        let (hrp, data) = bech32::decode(pubkey).map_err(|e| anyhow!("not a valid npub: {e}"))?;
+        if hrp.as_str() != "npub" {
+            return Err(anyhow!("nostr pubkey has wrong prefix: {hrp}"));
+        }
+        return XOnlyPublicKey::from_slice(&data)
+            .map_err(|e| anyhow!("not a valid nostr pubkey: {e}"));
+    }
+    pubkey
+        .parse::<XOnlyPublicKey>()
+        .map_err(|e| anyhow!("not a valid nostr pubkey: {e}"))
+}
+
+// NIP-57: the zap request is a signed but unpublished kind 9734 event
+pub fn build_zap_request(
+    keypair: &Keypair,
+    zap: &ZapInfo,
+    amount_msat: u64,
+    lnurl_url: &str,
+    created_at: i64,
+) -> Result<String, anyhow::Error> {
+    let lnurl = bech32::encode::<Bech32>(Hrp::parse("lnurl")?, lnurl_url.as_bytes())?;
+    let mut tags = vec![
+        json!(
+            ["relays".to_owned()]
+                .into_iter()
+                .chain(zap.relays.iter().cloned())
+                .collect::<Vec<String>>()
+        ),
+        json!(["amount", amount_msat.to_string()]),
+        json!(["lnurl", lnurl]),
+        json!(["p", parse_nostr_pubkey(&zap.recipient)?.to_string()]),
+    ];
+    if let Some(event) = &zap.event {
+        let event_id = <[u8; 32]>::from_hex(event)
+            .map_err(|e| anyhow!("`zap_event` is not a 32 byte hex event id: {e}"))?;
+        tags.push(json!(["e", event_id.to_lower_hex_string()]));
+    }
+    let pubkey = keypair.x_only_public_key().0.to_string();
+    let content = zap.content.clone().unwrap_or_default();
+
+    let serialized = json!([0, pubkey, created_at, ZAP_REQUEST_KIND, tags, content]).to_string();
+    let id = sha256::Hash::hash(serialized.as_bytes()).to_byte_array();
+    let sig = Secp256k1::new().sign_schnorr_no_aux_rand(&Message::from_digest(id), keypair);
+
+    Ok(json!({
+        "id": id.to_lower_hex_string(),
+        "pubkey": pubkey,
+        "created_at": created_at,
+        "kind": ZAP_REQUEST_KIND,
+        "tags": tags,
+        "content": content,
+        "sig": sig.serialize().to_lower_hex_string(),
+    })
+    .to_string())
+}
+
+#[test]
+fn test_build_zap_request() {
+    use bitcoin::secp256k1::schnorr::Signature;
+
+    let keypair = parse_nostr_key(&"01".repeat(32)).unwrap();
+    let recipient = Keypair::from_secret_key(
+        &Secp256k1::new(),
+        &SecretKey::from_slice(&[2u8; 32]).unwrap(),
+    )
+    .x_only_public_key()
+    .0;
+    let zap = ZapInfo {
+        recipient: recipient.to_string(),
+        event: Some("ab".repeat(32)),
+        relays: vec!["wss://relay.one".to_owned(), "wss://relay.two".to_owned()],
+        content: Some("zap!".to_owned()),
+    };
+    let zap_request = build_zap_request(
+        &keypair,
+        &zap,
+        21_000,
+        "https://site.com/.well-known/lnurlp/alice",
+        1_700_000_000,
+    )
+    .unwrap();
+    let event: serde_json::Value = serde_json::from_str(&zap_request).unwrap();
+    assert_eq!(event["kind"], 9734);
+    assert_eq!(event["content"], "zap!");
+    assert_eq!(
+        event["tags"][0],
+        json!(["relays", "wss://relay.one", "wss://relay.two"])
+    );
+    assert_eq!(event["tags"][1], json!(["amount", "21000"]));
+    assert!(event["tags"][2][1].as_str().unwrap().starts_with("lnurl1"));
+    assert_eq!(event["tags"][3], json!(["p", recipient.to_string()]));
+    assert_eq!(event["tags"][4], json!(["e", "ab".repeat(32)]));
+
+    let serialized = json!([
+        0,
+        event["pubkey"],
+        event["created_at"],
+        event["kind"],
+        event["tags"],
+        event["content"]
+    ])
+    .to_string();
+    let id = sha256::Hash::hash(serialized.as_bytes()).to_byte_array();
+    assert_eq!(event["id"], id.to_lower_hex_string());
+    let sig = Signature::from_slice(&Vec::<u8>::from_hex(event["sig"].as_str().unwrap()).unwrap())
+        .unwrap();
+    Secp256k1::new()
+        .verify_schnorr(
+            &sig,
+            &Message::from_digest(id),
+            &event["pubkey"].as_str().unwrap().parse().unwrap(),
+        )
+        .unwrap();
+
+    assert!(parse_nostr_key("nsec1invalid").is_err());
+    assert!(parse_nostr_pubkey("00").is_err());
+}
@@ -1,4 +1,4 @@
-use std::path::Path;
+use std::{path::Path, str::FromStr};
 
 use anyhow::anyhow;
 use cln_plugin::{ConfiguredPlugin, Plugin, options};
@@ -18,12 +18,24 @@ use cln_rpc::{
 use serde_json::{Map, json};
 
 use crate::{
+    OPT_PAYANY_ADDRESS_FALLBACK,
+    OPT_PAYANY_ADDRESS_METHOD_GRACE,
+    OPT_PAYANY_ADDRESS_PRIORITY,
     OPT_PAYANY_BUDGET_AMOUNT_MSAT,
     OPT_PAYANY_BUDGET_PER,
+    OPT_PAYANY_DNS_RESOLVER_TIMEOUT,
+    OPT_PAYANY_DNS_RESOLVERS,
+    OPT_PAYANY_DOH_SERVERS,
     OPT_PAYANY_HANDLE_PAY,
+    OPT_PAYANY_INVOICE_EXPIRY_MARGIN,
+    OPT_PAYANY_LNURL_REFETCH,
+    OPT_PAYANY_NOSTR_KEY,
+    OPT_PAYANY_PAYER_IDENTIFIER,
+    OPT_PAYANY_PAYER_NAME,
     OPT_PAYANY_STRICT_LNURL,
     PluginState,
-    structs::{Config, TimeUnit},
+    nostr::parse_nostr_key,
+    structs::{AddressMethod, Config, TimeUnit},
     util::at_or_above_version,
 };
 
@@ -67,18 +79,47 @@ pub fn get_startup_options(
     if let Some(handle) = plugin.option_str(OPT_PAYANY_STRICT_LNURL)? {
         check_option(&mut config, OPT_PAYANY_STRICT_LNURL, &handle)?;
     }
-    if config.budget_amount_msat.is_some() || config.budget_per.is_some() {
-        if config.budget_amount_msat.is_some() && config.budget_per.is_some() {
-            log::info!(
-                "Budget set to {}msat every {}seconds",
-                config.budget_amount_msat.unwrap().msat(),
-                config.budget_per.unwrap()
-            );
-        } else {
-            return Err(anyhow!("Incomplete Budget options!"));
-        }
-    } else {
-        log::info!("No Budget set!");
+    if let Some(name) = plugin.option_str(OPT_PAYANY_PAYER_NAME)? {
+        check_option(&mut config, OPT_PAYANY_PAYER_NAME, &name)?;
+    }
+    if let Some(identifier) = plugin.option_str(OPT_PAYANY_PAYER_IDENTIFIER)? {
+        check_option(&mut config, OPT_PAYANY_PAYER_IDENTIFIER, &identifier)?;
+    }
+    if let Some(nostr_key) = plugin.option_str(OPT_PAYANY_NOSTR_KEY)? {
+        check_option(&mut config, OPT_PAYANY_NOSTR_KEY, &nostr_key)?;
+    }
+    if let Some(margin) = plugin.option_str(OPT_PAYANY_INVOICE_EXPIRY_MARGIN)? {
+        check_option(&mut config, OPT_PAYANY_INVOICE_EXPIRY_MARGIN, &margin)?;
+    }
+    if let Some(refetch) = plugin.option_str(OPT_PAYANY_LNURL_REFETCH)? {
+        check_option(&mut config, OPT_PAYANY_LNURL_REFETCH, &refetch)?;
+    }
+    if let Some(priority) = plugin.option_str(OPT_PAYANY_ADDRESS_PRIORITY)? {
+        check_option(&mut config, OPT_PAYANY_ADDRESS_PRIORITY, &priority)?;
+    }
+    if let Some(fallback) = plugin.option_str(OPT_PAYANY_ADDRESS_FALLBACK)? {
+        check_option(&mut config, OPT_PAYANY_ADDRESS_FALLBACK, &fallback)?;
+    }
+    if let Some(resolvers) = plugin.option_str(OPT_PAYANY_DNS_RESOLVERS)? {
+        check_option(&mut config, OPT_PAYANY_DNS_RESOLVERS, &resolvers)?;
+    }
+    if let Some(timeout) = plugin.option_str(OPT_PAYANY_DNS_RESOLVER_TIMEOUT)? {
+        check_option(&mut config, OPT_PAYANY_DNS_RESOLVER_TIMEOUT, &timeout)?;
+    }
+    if let Some(grace) = plugin.option_str(OPT_PAYANY_ADDRESS_METHOD_GRACE)? {
+        check_option(&mut config, OPT_PAYANY_ADDRESS_METHOD_GRACE, &grace)?;
+    }
+    if let Some(servers) = plugin.option_str(OPT_PAYANY_DOH_SERVERS)? {
+        check_option(&mut config, OPT_PAYANY_DOH_SERVERS, &servers)?;
+    }
+    match (config.budget_amount_msat, config.budget_per) {
+        (Some(budget_amount_msat), Some(budget_per)) => log::info!(
+            "Budget set to {}msat every {}seconds",
+            budget_amount_msat.msat(),
+            budget_per
+        ),
+        (None, None) => log::info!("No Budget set!"),
+        _ => return Err(anyhow!("Incomplete Budget options!")),
     }
 
     Ok(())
@@ -128,7 +169,12 @@ pub async fn setconfig_callback(
 
 fn parse_option(name: &str, value: &serde_json::Value) -> Result<options::Value, anyhow::Error> {
     match name {
-        n if n.eq(OPT_PAYANY_BUDGET_AMOUNT_MSAT) => {
+        n if n.eq(OPT_PAYANY_BUDGET_AMOUNT_MSAT)
+            | n.eq(OPT_PAYANY_INVOICE_EXPIRY_MARGIN)
+            | n.eq(OPT_PAYANY_LNURL_REFETCH)
+            | n.eq(OPT_PAYANY_DNS_RESOLVER_TIMEOUT)
+            | n.eq(OPT_PAYANY_ADDRESS_METHOD_GRACE) =>
+        {
             if let Some(n_i64) = value.as_i64() {
                 return Ok(options::Value::Integer(n_i64));
             } else if let Some(n_str) = value.as_str() {
@@ -138,7 +184,10 @@ fn parse_option(name: &str, value: &serde_json::Value) -> Result<options::Value,
             }
             Err(anyhow!("{n} is not a valid integer!"))
         }
-        n if n.eq(OPT_PAYANY_HANDLE_PAY) | n.eq(OPT_PAYANY_STRICT_LNURL) => {
+        n if n.eq(OPT_PAYANY_HANDLE_PAY)
+            | n.eq(OPT_PAYANY_STRICT_LNURL)
+            | n.eq(OPT_PAYANY_ADDRESS_FALLBACK) =>
+        {
             if let Some(n_bool) = value.as_bool() {
                 return Ok(options::Value::Boolean(n_bool));
             } else if let Some(n_str) = value.as_str() {
@@ -182,11 +231,99 @@ fn check_option(
             }
         }
         n if n.eq(OPT_PAYANY_STRICT_LNURL) => config.strict_lnurl = value.as_bool().unwrap(),
+        n if n.eq(OPT_PAYANY_ADDRESS_FALLBACK) => {
+            config.address_fallback = value.as_bool().unwrap();
+        }
+        n if n.eq(OPT_PAYANY_PAYER_NAME) => {
+            config.payer_name = Some(value.as_str().unwrap().to_owned());
+        }
+        n if n.eq(OPT_PAYANY_PAYER_IDENTIFIER) => {
+            config.payer_identifier = Some(value.as_str().unwrap().to_owned());
+        }
+        n if n.eq(OPT_PAYANY_INVOICE_EXPIRY_MARGIN) => {
+            config.invoice_expiry_margin =
+                options_value_to_u64(OPT_PAYANY_INVOICE_EXPIRY_MARGIN, value.as_i64().unwrap(), 0)?;
+        }
+        n if n.eq(OPT_PAYANY_LNURL_REFETCH) => {
+            config.lnurl_refetch =
+                options_value_to_u64(OPT_PAYANY_LNURL_REFETCH, value.as_i64().unwrap(), 0)?;
+        }
+        n if n.eq(OPT_PAYANY_NOSTR_KEY) => {
+            config.nostr_key = Some(parse_nostr_key(value.as_str().unwrap())?);
+        }
+        n if n.eq(OPT_PAYANY_ADDRESS_PRIORITY) => {
+            config.address_priority = parse_address_priority(value.as_str().unwrap())?;
+        }
+        n if n.eq(OPT_PAYANY_DNS_RESOLVERS) => {
+            config.dns_resolvers = parse_dns_resolvers(value.as_str().unwrap())?;
+        }
+        n if n.eq(OPT_PAYANY_DNS_RESOLVER_TIMEOUT) => {
+            config.dns_resolver_timeout =
+                options_value_to_u64(OPT_PAYANY_DNS_RESOLVER_TIMEOUT, value.as_i64().unwrap(), 1)?;
+        }
+        n if n.eq(OPT_PAYANY_ADDRESS_METHOD_GRACE) => {
+            config.address_method_grace =
+                options_value_to_u64(OPT_PAYANY_ADDRESS_METHOD_GRACE, value.as_i64().unwrap(), 0)?;
+        }
+        n if n.eq(OPT_PAYANY_DOH_SERVERS) => {
+            config.doh_servers = parse_doh_servers(value.as_str().unwrap())?;
+        }
         _ => return Err(anyhow!("Unknown option: {name}")),
     }
     Ok(())
 }
 
+fn parse_address_priority(input: &str) -> Result<Vec<AddressMethod>, anyhow::Error> {
+    let mut priority = Vec::new();
+    for method in input.split(',') {
+        let method: AddressMethod = method.parse().map_err(|e: String| {
+            anyhow!("{OPT_PAYANY_ADDRESS_PRIORITY}: {e}, valid are `lnurl`, `bip353` and `blip32`")
+        })?;
+        if priority.contains(&method) {
+            return Err(anyhow!(
+                "{OPT_PAYANY_ADDRESS_PRIORITY}: `{method}` is listed more than once"
+            ));
+        }
+        priority.push(method);
+    }
+    Ok(priority)
+}
+
+// An empty list finds the resolvers in gossip again
+fn parse_dns_resolvers(input: &str) -> Result<Vec<PublicKey>, anyhow::Error> {
+    input
+        .split(',')
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+        .map(|r| {
+            PublicKey::from_str(r)
+                .map_err(|e| anyhow!("{OPT_PAYANY_DNS_RESOLVERS}: invalid node id `{r}`: {e}"))
+        })
+        .collect()
+}
+
+// The DNS-over-HTTPS servers learn the bip353 names but can't forge the proofs
+fn parse_doh_servers(input: &str) -> Result<Vec<String>, anyhow::Error> {
+    let servers = input
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| match url::Url::parse(s) {
+            Ok(url) if url.scheme() == "https" => Ok(s.to_owned()),
+            Ok(_) => Err(anyhow!(
+                "{OPT_PAYANY_DOH_SERVERS}: `{s}` is not an https url"
+            )),
+            Err(e) => Err(anyhow!("{OPT_PAYANY_DOH_SERVERS}: invalid url `{s}`: {e}")),
+        })
+        .collect::<Result<Vec<String>, anyhow::Error>>()?;
+    if servers.is_empty() {
+        return Err(anyhow!(
+            "{OPT_PAYANY_DOH_SERVERS}: at least one server is needed"
+        ));
+    }
+    Ok(servers)
+}
+
 fn options_value_to_u64(name: &str, value: i64, gteq: u64) -> Result<u64, anyhow::Error> {
     if value >= 0 {
         validate_u64_input(value as u64, name, gteq)
@@ -412,6 +549,7 @@ pub async fn parse_pay_args(plugin: Plugin<PluginState>) -> Result<(), anyhow::E
             }
         }
         config.payargs.push("message".to_owned());
+        config.payargs.push("idempotency_key".to_owned());
     }
 
     if let Some(hxp) = help_xpay.first() {
@@ -426,6 +564,7 @@ pub async fn parse_pay_args(plugin: Plugin<PluginState>) -> Result<(), anyhow::E
             }
         }
         config.xpayargs.push("message".to_owned());
+        config.xpayargs.push("idempotency_key".to_owned());
     }
 
     if let Some(hrp) = help_renepay.first() {
@@ -440,6 +579,7 @@ pub async fn parse_pay_args(plugin: Plugin<PluginState>) -> Result<(), anyhow::E
             }
         }
         config.renepayargs.push("message".to_owned());
+        config.renepayargs.push("idempotency_key".to_owned());
     }
 
     if plugin
@@ -477,3 +617,43 @@ fn test_time_parse() {
     let result = parse_time_period("3    hours").unwrap();
     assert_eq!(result, 10800);
 }
+
+#[test]
+fn test_address_priority_parse() {
+    assert_eq!(
+        parse_address_priority("bip353,lnurl").unwrap(),
+        vec![AddressMethod::Bip353, AddressMethod::Lnurl]
+    );
+    assert_eq!(
+        parse_address_priority(" LNURL ").unwrap(),
+        vec![AddressMethod::Lnurl]
+    );
+    assert!(parse_address_priority("").is_err());
+    assert!(parse_address_priority("bip353,dns").is_err());
+    assert!(parse_address_priority("lnurl,lnurl").is_err());
+}
+
+#[test]
+fn test_dns_resolvers_parse() {
+    let resolver = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    assert_eq!(
+        parse_dns_resolvers(&format!(" {resolver} ")).unwrap(),
+        vec![PublicKey::from_str(resolver).unwrap()]
+    );
+    assert!(parse_dns_resolvers("").unwrap().is_empty());
+    assert!(parse_dns_resolvers(&format!("{resolver},02ad")).is_err());
+}
+
+#[test]
+fn test_doh_servers_parse() {
+    assert_eq!(
+        parse_doh_servers(" https://dns.example.com/dns-query ,https://doh.example.org/q").unwrap(),
+        vec![
+            "https://dns.example.com/dns-query".to_owned(),
+            "https://doh.example.org/q".to_owned()
+        ]
+    );
+    assert!(parse_doh_servers("").is_err());
+    assert!(parse_doh_servers("http://dns.example.com/dns-query").is_err());
+    assert!(parse_doh_servers("dns.example.com").is_err());
+}
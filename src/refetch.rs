@@ -0,0 +1,198 @@
+use std::path::Path;
+
+use cln_plugin::Plugin;
+use cln_rpc::{ClnRpc, RpcError};
+use serde_json::{Map, json};
+
+use crate::{
+    budget::budget_check,
+    fetch::resolve_invstring_via,
+    hooks::check_earlier_payment,
+    idempotency::save_idempotent_invoice,
+    parse::convert_pay_to_xpay,
+    structs::{AddressMethod, PAYANY_PARAMS, Paycmd, PluginState},
+    util::earlier_payment,
+};
+
+// Pay errors after which a fresh invoice from the same service may succeed:
+// PAY_DESTINATION_PERM_FAIL and PAY_INVOICE_EXPIRED
+const REFETCH_ERROR_CODES: [i32; 2] = [203, 207];
+
+pub fn invstring_param(params: &Map<String, serde_json::Value>) -> Option<&str> {
+    params
+        .get("invstring")
+        .or_else(|| params.get("bolt11"))
+        .and_then(|i| i.as_str())
+}
+
+// Pays a LNURL invoice itself instead of replacing the rpc command, so that a
+// new invoice can be fetched from the service if the first one expired or was
+// rejected. Every new invoice is checked against the budget again.
+#[allow(clippy::too_many_arguments)]
+pub async fn pay_with_refetch(
+    plugin: Plugin<PluginState>,
+    paycmd: Paycmd,
+    params: Map<String, serde_json::Value>,
+    original_params: Map<String, serde_json::Value>,
+    convert_to_xpay: bool,
+    max_refetch: u64,
+    idempotency_key: Option<String>,
+    address_method: Option<AddressMethod>,
+) -> serde_json::Value {
+    match pay_refetching(
+        plugin,
+        paycmd,
+        params,
+        &original_params,
+        convert_to_xpay,
+        max_refetch,
+        idempotency_key.as_deref(),
+    )
+    .await
+    {
+        Ok(mut o) => {
+            if let (Some(method), Some(result_obj)) = (address_method, o.as_object_mut()) {
+                result_obj.insert("address_method".to_owned(), json!(method.to_string()));
+            }
+            json!({"return": {"result": o}})
+        }
+        Err(e) => json!({"return": {"error": e}}),
+    }
+}
+
+pub async fn pay_refetching(
+    plugin: Plugin<PluginState>,
+    paycmd: Paycmd,
+    mut params: Map<String, serde_json::Value>,
+    original_params: &Map<String, serde_json::Value>,
+    convert_to_xpay: bool,
+    max_refetch: u64,
+    idempotency_key: Option<&str>,
+) -> Result<serde_json::Value, RpcError> {
+    let mut rpc = ClnRpc::new(
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file),
+    )
+    .await
+    .map_err(|e| payany_error(format!("payany could not connect to rpc: {e}")))?;
+    let mut refetches = 0;
+    loop {
+        let pay_error = match pay_own_invoice(plugin.clone(), &mut rpc, paycmd, &params).await {
+            Ok(o) => return Ok(o),
+            Err(e) => e,
+        };
+        if refetches >= max_refetch
+            || !can_refetch(
+                plugin.clone(),
+                &mut rpc,
+                &pay_error,
+                invstring_param(&params).unwrap_or_default(),
+            )
+            .await
+        {
+            return Err(pay_error);
+        }
+        refetches += 1;
+        log::info!(
+            "Paying lnurl invoice failed: {}, fetching a new invoice ({refetches}/{max_refetch})",
+            pay_error.message
+        );
+
+        params = original_params.clone();
+        // A lightning address must get its new invoice from the same LNURL service
+        if let Err(e) =
+            resolve_invstring_via(plugin.clone(), &mut params, vec![AddressMethod::Lnurl]).await
+        {
+            return Err(payany_error(format!(
+                "payany could not fetch a new invoice after `{}`: {e}",
+                pay_error.message
+            )));
+        }
+        prepare_own_payment(
+            plugin.clone(),
+            &mut params,
+            paycmd,
+            idempotency_key,
+            convert_to_xpay,
+        )
+        .await?;
+    }
+}
+
+// A new invoice must not be fetched while the failed one might still get paid
+async fn can_refetch(
+    plugin: Plugin<PluginState>,
+    rpc: &mut ClnRpc,
+    pay_error: &RpcError,
+    invstring: &str,
+) -> bool {
+    if !pay_error
+        .code
+        .is_some_and(|c| REFETCH_ERROR_CODES.contains(&c))
+    {
+        return false;
+    }
+    match earlier_payment(plugin, rpc, invstring).await {
+        Ok(None) => true,
+        Ok(Some((payment_hash, status, _payments))) => {
+            log::info!("Not fetching a new invoice, payment of {payment_hash} is {status}");
+            false
+        }
+        Err(e) => {
+            log::info!("Not fetching a new invoice, could not check for earlier payments: {e}");
+            false
+        }
+    }
+}
+
+// The hook is told to let payments of invoices in `own_invoices` through
+pub async fn pay_own_invoice(
+    plugin: Plugin<PluginState>,
+    rpc: &mut ClnRpc,
+    paycmd: Paycmd,
+    params: &Map<String, serde_json::Value>,
+) -> Result<serde_json::Value, RpcError> {
+    let invstring = invstring_param(params).unwrap_or_default().to_owned();
+    plugin.state().own_invoices.lock().insert(invstring.clone());
+    let result = rpc
+        .call_raw::<serde_json::Value, _>(paycmd.method(), params)
+        .await;
+    plugin.state().own_invoices.lock().remove(&invstring);
+    result
+}
+
+// Does what the hook does with freshly resolved params before paying them, a
+// new invoice may have another payment hash and amount than the checked one
+pub async fn prepare_own_payment(
+    plugin: Plugin<PluginState>,
+    params: &mut Map<String, serde_json::Value>,
+    paycmd: Paycmd,
+    idempotency_key: Option<&str>,
+    convert_to_xpay: bool,
+) -> Result<(), RpcError> {
+    params.retain(|param, _| !PAYANY_PARAMS.contains(&param.as_str()));
+    check_earlier_payment(plugin.clone(), params).await?;
+    let original_paycmd = if convert_to_xpay { Paycmd::Pay } else { paycmd };
+    budget_check(plugin.clone(), params, original_paycmd)
+        .await
+        .map_err(|e| payany_error(format!("payany budget exceeded: {e}")))?;
+    if let Some(key) = idempotency_key {
+        let invstring = invstring_param(params).unwrap_or_default();
+        save_idempotent_invoice(plugin.clone(), key, invstring, true)
+            .await
+            .map_err(|e| payany_error(format!("payany: {e}")))?;
+    }
+    if convert_to_xpay {
+        convert_pay_to_xpay(plugin, params)
+            .await
+            .map_err(|e| payany_error(format!("payany conversion to xpay failed: {e}")))?;
+    }
+    Ok(())
+}
+
+pub fn payany_error(message: String) -> RpcError {
+    RpcError {
+        code: Some(-32602),
+        message,
+        data: None,
+    }
+}
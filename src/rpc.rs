@@ -1,10 +1,56 @@
+use std::path::Path;
+
 use anyhow::{Error, anyhow};
+use bitcoin::{
+    hashes::Hash,
+    hex::{DisplayHex, FromHex},
+};
 use cln_plugin::Plugin;
+use cln_rpc::{
+    ClnRpc,
+    RpcError,
+    primitives::{Amount, Sha256},
+};
 use serde_json::{Map, json};
 
-use crate::{PluginState, fetch::resolve_invstring};
+use crate::{
+    PluginState,
+    auth::auth_lnurl,
+    channel::channel_lnurl,
+    datastore::load_lnurl_record,
+    fetch::{
+        bip353_address,
+        fetch_offer_invoice,
+        quote_invstring,
+        resolve_invstring,
+        resolve_quote,
+    },
+    idempotency::{check_idempotency_key, idempotency_key_param, save_idempotent_invoice},
+    lnurl::{fetch_lnurl_verify, lnurl_error_data},
+    metadata::metadata_to_json,
+    structs::IdempotentCall,
+    successaction::success_action_to_json,
+    util::local_payment_status,
+    withdraw::withdraw_lnurl,
+};
 
-const PAYANYARGS: [&str; 3] = ["invstring", "amount_msat", "message"];
+const PAYANYAUTHARGS: [&str; 1] = ["lnurl"];
+const PAYANYCHANNELARGS: [&str; 2] = ["lnurl", "private"];
+const PAYANYWITHDRAWARGS: [&str; 3] = ["lnurl", "amount_msat", "label"];
+const PAYANYARGS: [&str; 12] = [
+    "invstring",
+    "amount_msat",
+    "message",
+    "payer_name",
+    "payer_identifier",
+    "zap_pubkey",
+    "zap_event",
+    "zap_relays",
+    "zap_content",
+    "quote",
+    "quote_id",
+    "idempotency_key",
+];
 
 pub async fn payany(
     plugin: Plugin<PluginState>,
@@ -18,12 +64,290 @@ pub async fn payany(
             params.insert(PAYANYARGS[i].to_owned(), arg.clone());
         }
     }
-    match resolve_invstring(plugin, &mut params).await {
+    let quote = if let Some(q) = params.get("quote") {
+        q.as_bool()
+            .ok_or_else(|| anyhow!("`quote` must be a boolean"))?
+    } else {
+        false
+    };
+    if quote {
+        return quote_invstring(plugin, &params).await;
+    }
+    let idempotency_key = idempotency_key_param(&params)?;
+    if let Some(key) = &idempotency_key {
+        match check_idempotency_key(plugin.clone(), key).await? {
+            IdempotentCall::New => (),
+            IdempotentCall::Complete(result)
+            | IdempotentCall::Pending(result)
+            | IdempotentCall::Retry(_, result) => return Ok(result),
+        }
+    }
+    let original_params = params.clone();
+    let resolved = if params.contains_key("quote_id") {
+        resolve_quote(plugin.clone(), &mut params).await
+    } else {
+        resolve_invstring(plugin.clone(), &mut params).await
+    };
+    let resolution = match resolved {
         Ok(o) => o,
         Err(e) => {
-            params.remove("message");
+            if let Some(data) = lnurl_error_data(&e) {
+                return Err(anyhow!(json!(RpcError {
+                    code: Some(-32602),
+                    message: e.to_string(),
+                    data: Some(data),
+                })));
+            }
             return Err(anyhow!(e.to_string()));
         }
+    };
+    let offer = fetch_offer_invoice(
+        plugin.clone(),
+        &mut params,
+        bip353_address(&resolution, &original_params),
+    )
+    .await?;
+    let invoice = params
+        .get("invstring")
+        .unwrap()
+        .as_str()
+        .unwrap()
+        .to_owned();
+    let mut result = json!({"invoice":invoice});
+    if let Some(offer) = offer {
+        result
+            .as_object_mut()
+            .unwrap()
+            .insert("offer".to_owned(), json!(offer));
+    }
+    if let Some(key) = &idempotency_key {
+        save_idempotent_invoice(plugin, key, &invoice, false).await?;
+        result
+            .as_object_mut()
+            .unwrap()
+            .insert("idempotency_key".to_owned(), json!(key));
+    }
+    if let Some(success_action) = &resolution.success_action {
+        result.as_object_mut().unwrap().insert(
+            "success_action".to_owned(),
+            success_action_to_json(success_action, None),
+        );
+    }
+    if let Some(verify) = resolution.verify {
+        result
+            .as_object_mut()
+            .unwrap()
+            .insert("verify".to_owned(), json!(verify));
+    }
+    if let Some(address_method) = resolution.address_method {
+        result.as_object_mut().unwrap().insert(
+            "address_method".to_owned(),
+            json!(address_method.to_string()),
+        );
+    }
+    if let Some(bip353) = &resolution.bip353 {
+        result.as_object_mut().unwrap().insert(
+            "bip353".to_owned(),
+            json!({
+                "uri": bip353.uri,
+                "offer": bip353.offer,
+                "proof": bip353.proof.to_lower_hex_string(),
+                "valid_from": bip353.valid_from,
+                "expires": bip353.expires,
+            }),
+        );
+    }
+    if let Some(metadata) = &resolution.metadata {
+        result
+            .as_object_mut()
+            .unwrap()
+            .insert("metadata".to_owned(), metadata_to_json(metadata));
+    }
+    Ok(result)
+}
+
+pub async fn payany_verify(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let payment_hash_val = if let Some(args_obj) = args.as_object() {
+        args_obj.get("payment_hash")
+    } else if let Some(args_arr) = args.as_array() {
+        args_arr.first()
+    } else {
+        None
+    }
+    .ok_or_else(|| anyhow!("missing required parameter: `payment_hash`"))?;
+    let payment_hash: Sha256 = serde_json::from_value(payment_hash_val.clone())
+        .map_err(|e| anyhow!("`payment_hash` is not a valid hash: {e}"))?;
+
+    let (record, _generation) = load_lnurl_record(plugin.clone(), &payment_hash)
+        .await?
+        .ok_or_else(|| anyhow!("no LNURL invoice known for payment hash {payment_hash}"))?;
+    let verify_url = record.verify.ok_or_else(|| {
+        anyhow!("LNURL service did not provide a verify url for payment hash {payment_hash}")
+    })?;
+
+    let config = plugin.state().config.lock().clone();
+    let verify = fetch_lnurl_verify(&config, &verify_url).await?;
+    if verify.pr != record.invoice {
+        return Err(anyhow!(
+            "LNURL verify returned a different invoice: {}",
+            verify.pr
+        ));
+    }
+
+    let mut rpc = ClnRpc::new(
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file),
+    )
+    .await?;
+    let (local_status, payments) = local_payment_status(&mut rpc, payment_hash).await?;
+    let local_preimage = payments.iter().find_map(|p| p.payment_preimage);
+
+    let mut result = json!({
+        "payment_hash": payment_hash.to_string(),
+        "verify": verify_url,
+        "settled": verify.settled,
+        "local_status": local_status,
+    });
+    let result_obj = result.as_object_mut().unwrap();
+    if let Some(local_pre) = local_preimage {
+        result_obj.insert(
+            "local_preimage".to_owned(),
+            json!(local_pre.to_vec().to_lower_hex_string()),
+        );
+    }
+    if let Some(preimage) = &verify.preimage {
+        let preimage_bytes = <[u8; 32]>::from_hex(preimage)
+            .map_err(|e| anyhow!("LNURL verify returned an invalid preimage: {e}"))?;
+        let preimage_valid = Sha256::hash(&preimage_bytes) == payment_hash;
+        result_obj.insert("preimage".to_owned(), json!(preimage));
+        result_obj.insert("preimage_valid".to_owned(), json!(preimage_valid));
+        if let Some(local_pre) = local_preimage {
+            result_obj.insert(
+                "preimage_matches".to_owned(),
+                json!(local_pre.to_vec() == preimage_bytes),
+            );
+        }
+        if !preimage_valid {
+            log::warn!("LNURL verify returned a preimage not matching {payment_hash}");
+        }
+    }
+    Ok(result)
+}
+
+pub async fn payany_withdraw(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let (lnurl, params) = lnurl_rpc_params(&args, &PAYANYWITHDRAWARGS, "payany-withdraw")?;
+    let amount_msat = if let Some(amt) = params.get("amount_msat") {
+        Some(Amount::from_msat(amt.as_u64().ok_or_else(|| {
+            anyhow!("`amount_msat` must be an integer")
+        })?))
+    } else {
+        None
+    };
+    let label = if let Some(lbl) = params.get("label") {
+        Some(
+            lbl.as_str()
+                .ok_or_else(|| anyhow!("`label` must be a string"))?
+                .to_owned(),
+        )
+    } else {
+        None
+    };
+
+    withdraw_lnurl(plugin, &lnurl, amount_msat, label).await
+}
+
+pub async fn payany_auth(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let (lnurl, _params) = lnurl_rpc_params(&args, &PAYANYAUTHARGS, "payany-auth")?;
+
+    auth_lnurl(plugin, &lnurl).await
+}
+
+pub async fn payany_channel(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let (lnurl, params) = lnurl_rpc_params(&args, &PAYANYCHANNELARGS, "payany-channel")?;
+    let private = if let Some(prv) = params.get("private") {
+        prv.as_bool()
+            .ok_or_else(|| anyhow!("`private` must be a boolean"))?
+    } else {
+        false
+    };
+
+    channel_lnurl(plugin, &lnurl, private).await
+}
+
+// The LNURL rpc methods take the `lnurl` first, by name or by position
+fn lnurl_rpc_params(
+    args: &serde_json::Value,
+    arg_names: &[&str],
+    method: &str,
+) -> Result<(String, Map<String, serde_json::Value>), Error> {
+    let mut params = Map::new();
+    if let Some(args_obj) = args.as_object() {
+        params.clone_from(args_obj);
+    } else if let Some(args_arr) = args.as_array() {
+        if args_arr.len() > arg_names.len() {
+            return Err(anyhow!("too many arguments for {method}"));
+        }
+        for (i, arg) in args_arr.iter().enumerate() {
+            params.insert(arg_names[i].to_owned(), arg.clone());
+        }
     }
-    Ok(json!({"invoice":format!("{}", params.get("invstring").unwrap().as_str().unwrap())}))
+    let lnurl = params
+        .get("lnurl")
+        .ok_or_else(|| anyhow!("missing required parameter: `lnurl`"))?
+        .as_str()
+        .ok_or_else(|| anyhow!("`lnurl` must be a string"))?
+        .to_owned();
+    Ok((lnurl, params))
+}
+
+#[test]
+fn test_lnurl_rpc_params() {
+    let (lnurl, params) = lnurl_rpc_params(
+        &json!(["lnurl1abc", 1_000, "withdraw"]),
+        &PAYANYWITHDRAWARGS,
+        "payany-withdraw",
+    )
+    .unwrap();
+    assert_eq!(lnurl, "lnurl1abc");
+    assert_eq!(params["amount_msat"], 1_000);
+    assert_eq!(params["label"], "withdraw");
+
+    let (lnurl, params) = lnurl_rpc_params(
+        &json!({"lnurl": "lnurl1abc", "private": true}),
+        &PAYANYCHANNELARGS,
+        "payany-channel",
+    )
+    .unwrap();
+    assert_eq!(lnurl, "lnurl1abc");
+    assert_eq!(params["private"], true);
+
+    assert_eq!(
+        lnurl_rpc_params(&json!(["lnurl1abc", 1]), &PAYANYAUTHARGS, "payany-auth")
+            .unwrap_err()
+            .to_string(),
+        "too many arguments for payany-auth"
+    );
+    assert_eq!(
+        lnurl_rpc_params(&json!({}), &PAYANYAUTHARGS, "payany-auth")
+            .unwrap_err()
+            .to_string(),
+        "missing required parameter: `lnurl`"
+    );
+    assert_eq!(
+        lnurl_rpc_params(&json!([1]), &PAYANYAUTHARGS, "payany-auth")
+            .unwrap_err()
+            .to_string(),
+        "`lnurl` must be a string"
+    );
 }
@@ -1,23 +1,55 @@
-use std::{str::FromStr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+    sync::Arc,
+};
 
 use anyhow::anyhow;
-use cln_rpc::primitives::Amount;
+use bitcoin::secp256k1::{Keypair, PublicKey};
+use cln_rpc::primitives::{Amount, Sha256};
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, json};
+use tokio::sync::oneshot;
 
 pub const URI_SCHEMES: [&str; 3] = ["lightning:", "lno:", "lnurl:"];
+pub const LUD17_SCHEMES: [(&str, LnurlTag); 4] = [
+    ("lnurlp://", LnurlTag::PayRequest),
+    ("lnurlw://", LnurlTag::WithdrawRequest),
+    ("lnurlc://", LnurlTag::ChannelRequest),
+    ("keyauth://", LnurlTag::Login),
+];
+pub const PAYANY_PARAMS: [&str; 8] = [
+    "message",
+    "idempotency_key",
+    "payer_name",
+    "payer_identifier",
+    "zap_pubkey",
+    "zap_event",
+    "zap_relays",
+    "zap_content",
+];
+
+// Waits for the `dnssec_proof` of a bLIP-32 query, keyed by the path_id of its reply path
+pub type DnssecReply = oneshot::Sender<Vec<u8>>;
 
 #[derive(Debug, Clone)]
 pub struct PluginState {
     pub config: Arc<Mutex<Config>>,
     pub pay_index: Arc<Mutex<u64>>,
+    pub quotes: Arc<Mutex<HashMap<String, LnurlQuote>>>,
+    pub own_invoices: Arc<Mutex<HashSet<String>>>,
+    pub dnssec_queries: Arc<Mutex<HashMap<[u8; 32], DnssecReply>>>,
 }
 impl Default for PluginState {
     fn default() -> PluginState {
         PluginState {
             config: Arc::new(Mutex::new(Config::default())),
             pay_index: Arc::new(Mutex::new(0)),
+            quotes: Arc::new(Mutex::new(HashMap::new())),
+            own_invoices: Arc::new(Mutex::new(HashSet::new())),
+            dnssec_queries: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 }
@@ -34,6 +66,96 @@ pub struct Config {
     pub version: String,
     pub ignore_deprecated_pays: bool,
     pub tor_proxy: Option<String>,
+    pub payer_name: Option<String>,
+    pub payer_identifier: Option<String>,
+    pub nostr_key: Option<Keypair>,
+    pub network: String,
+    pub invoice_expiry_margin: u64,
+    pub lnurl_refetch: u64,
+    pub address_priority: Vec<AddressMethod>,
+    pub address_fallback: bool,
+    pub dns_resolvers: Vec<PublicKey>,
+    pub dns_resolver_timeout: u64,
+    pub address_method_grace: u64,
+    pub doh_servers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressMethod {
+    Lnurl,
+    Bip353,
+    Blip32,
+}
+impl fmt::Display for AddressMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressMethod::Lnurl => write!(f, "lnurl"),
+            AddressMethod::Bip353 => write!(f, "bip353"),
+            AddressMethod::Blip32 => write!(f, "blip32"),
+        }
+    }
+}
+impl FromStr for AddressMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "lnurl" => Ok(AddressMethod::Lnurl),
+            "bip353" => Ok(AddressMethod::Bip353),
+            "blip32" => Ok(AddressMethod::Blip32),
+            _ => Err(format!("Unknown address resolution method: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LnurlTag {
+    PayRequest,
+    WithdrawRequest,
+    ChannelRequest,
+    Login,
+}
+impl fmt::Display for LnurlTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LnurlTag::PayRequest => write!(f, "payRequest"),
+            LnurlTag::WithdrawRequest => write!(f, "withdrawRequest"),
+            LnurlTag::ChannelRequest => write!(f, "channelRequest"),
+            LnurlTag::Login => write!(f, "login"),
+        }
+    }
+}
+impl FromStr for LnurlTag {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "payrequest" => Ok(LnurlTag::PayRequest),
+            "withdrawrequest" => Ok(LnurlTag::WithdrawRequest),
+            "channelrequest" => Ok(LnurlTag::ChannelRequest),
+            "login" => Ok(LnurlTag::Login),
+            _ => Err(format!("Unsupported LNURL tag: {s}")),
+        }
+    }
+}
+impl LnurlTag {
+    pub fn protocol(&self) -> &'static str {
+        match self {
+            LnurlTag::PayRequest => "LNURL-pay (LUD-06)",
+            LnurlTag::WithdrawRequest => "LNURL-withdraw (LUD-03)",
+            LnurlTag::ChannelRequest => "LNURL-channel (LUD-02)",
+            LnurlTag::Login => "LNURL-auth (LUD-04)",
+        }
+    }
+
+    pub fn rpc_method(&self) -> &'static str {
+        match self {
+            LnurlTag::PayRequest => "payany",
+            LnurlTag::WithdrawRequest => "payany-withdraw",
+            LnurlTag::ChannelRequest => "payany-channel",
+            LnurlTag::Login => "payany-auth",
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -42,6 +164,15 @@ pub enum Paycmd {
     Xpay,
     Renepay,
 }
+impl Paycmd {
+    pub fn method(&self) -> &'static str {
+        match self {
+            Paycmd::Pay => "pay",
+            Paycmd::Xpay => "xpay",
+            Paycmd::Renepay => "renepay",
+        }
+    }
+}
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct RpcCommand {
@@ -112,7 +243,7 @@ impl ParamValue {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LnurlpConfig {
     pub callback: String,
     #[serde(rename = "maxSendable")]
@@ -124,12 +255,218 @@ pub struct LnurlpConfig {
     #[serde(rename = "commentAllowed")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub comment_allowed: Option<u64>,
+    #[serde(rename = "payerData")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payer_data: Option<PayerDataRequest>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disposable: Option<bool>,
+    #[serde(rename = "allowsNostr")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allows_nostr: Option<bool>,
+    #[serde(rename = "nostrPubkey")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nostr_pubkey: Option<String>,
+    #[serde(skip)]
+    pub parsed_metadata: LnurlMetadata,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LnurlMetadata {
+    pub description: Option<String>,
+    pub long_description: Option<String>,
+    pub image: Option<MetadataImage>,
+    pub identifier: Option<String>,
+    pub email: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MetadataImage {
+    pub mime_type: String,
+    pub data: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PayerDataRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<PayerDataField>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<PayerDataField>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identifier: Option<PayerDataField>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<PayerDataField>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<PayerDataAuthField>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PayerDataField {
+    #[serde(default)]
+    pub mandatory: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PayerDataAuthField {
+    #[serde(default)]
+    pub mandatory: bool,
+    pub k1: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PayerInfo {
+    pub message: Option<String>,
+    pub name: Option<String>,
+    pub identifier: Option<String>,
+    pub zap: Option<ZapInfo>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ZapInfo {
+    pub recipient: String,
+    pub event: Option<String>,
+    pub relays: Vec<String>,
+    pub content: Option<String>,
+}
+
+// What the description hash of a LNURL invoice commits to
+#[derive(Debug, Clone)]
+pub enum CommittedDescription {
+    // LUD-06 metadata and the LUD-18 payerdata we sent
+    Metadata(String),
+    // NIP-57 zap request we sent, instead of the metadata
+    ZapRequest(String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LnurlpCallback {
     pub pr: String,
     pub routes: Vec<String>,
+    // Parsed leniently by `parse_success_action`, so an unknown action doesn't
+    // make the invoice unpayable
+    #[serde(rename = "successAction")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub success_action: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disposable: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LnurlcConfig {
+    pub uri: String,
+    pub callback: String,
+    pub k1: String,
+    pub tag: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LnurlwConfig {
+    pub callback: String,
+    pub k1: String,
+    #[serde(rename = "defaultDescription")]
+    pub default_description: String,
+    #[serde(rename = "minWithdrawable")]
+    pub min_withdrawable: u64,
+    #[serde(rename = "maxWithdrawable")]
+    pub max_withdrawable: u64,
+    pub tag: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LnurlVerify {
+    pub settled: bool,
+    pub preimage: Option<String>,
+    pub pr: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "tag", rename_all = "lowercase")]
+pub enum SuccessAction {
+    Message {
+        message: String,
+    },
+    Url {
+        description: String,
+        url: String,
+    },
+    Aes {
+        description: String,
+        ciphertext: String,
+        iv: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LnurlInvoiceRecord {
+    pub invoice: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub success_action: Option<SuccessAction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify: Option<String>,
+    #[serde(default)]
+    pub settled: bool,
+    #[serde(default = "default_disposable")]
+    pub disposable: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdempotencyRecord {
+    pub invoice: String,
+    pub payment_hash: Sha256,
+}
+
+pub enum IdempotentCall {
+    New,
+    Complete(serde_json::Value),
+    Pending(serde_json::Value),
+    Retry(IdempotencyRecord, serde_json::Value),
+}
+
+fn default_disposable() -> bool {
+    true
+}
+
+pub enum LnurlpTarget {
+    Lnurl(String),
+    Lnaddress(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct LnurlQuote {
+    pub invstring: String,
+    pub config_url: String,
+    pub lnaddress: Option<String>,
+    pub lnurlp_config: LnurlpConfig,
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Resolution {
+    pub success_action: Option<SuccessAction>,
+    pub verify: Option<String>,
+    pub metadata: Option<LnurlMetadata>,
+    pub from_lnurl: bool,
+    pub address_method: Option<AddressMethod>,
+    pub bip353: Option<Bip353Resolution>,
+}
+
+// What the concurrent lookups of a lightning address found: LNURL only fetched
+// the config, the invoice is fetched once LNURL was chosen. The bip353 methods
+// resolved the offer into their own copy of the params.
+#[derive(Debug)]
+pub enum AddressLookup {
+    Lnurl(Box<LnurlpConfig>),
+    Offer(Box<Resolution>, Map<String, serde_json::Value>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Bip353Resolution {
+    pub uri: String,
+    pub offer: String,
+    pub proof: Vec<u8>,
+    pub valid_from: u64,
+    pub expires: u64,
 }
 
 #[derive(Debug)]
@@ -155,3 +492,86 @@ impl FromStr for TimeUnit {
         }
     }
 }
+
+// LUD-06: `{"status":"ERROR","reason":...}` responses and non-2xx answers of a service
+#[derive(Debug)]
+pub struct LnurlServiceError {
+    pub what: String,
+    pub http_status: Option<u16>,
+    pub reason: Option<String>,
+}
+impl fmt::Display for LnurlServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = self.reason.as_deref().unwrap_or("no reason given");
+        if let Some(status) = self.http_status {
+            write!(
+                f,
+                "LNURL: got bad status for {}: {status}: {reason}",
+                self.what
+            )
+        } else {
+            write!(f, "LNURL: {} returned an error: {reason}", self.what)
+        }
+    }
+}
+impl std::error::Error for LnurlServiceError {}
+impl LnurlServiceError {
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut result = json!({"lnurl_request": self.what});
+        let result_obj = result.as_object_mut().unwrap();
+        if let Some(status) = self.http_status {
+            result_obj.insert("http_status".to_owned(), json!(status));
+        }
+        if let Some(reason) = &self.reason {
+            result_obj.insert("reason".to_owned(), json!(reason));
+        }
+        result
+    }
+}
+
+#[derive(Debug)]
+pub struct AddressMethodFailure {
+    pub method: AddressMethod,
+    pub message: String,
+    pub data: Option<serde_json::Value>,
+}
+
+// Every method of `payany-address-priority` failed for a `user@domain` address
+#[derive(Debug)]
+pub struct AddressResolutionError {
+    pub address: String,
+    pub failures: Vec<AddressMethodFailure>,
+}
+impl fmt::Display for AddressResolutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "could not resolve {}", self.address)?;
+        for (i, failure) in self.failures.iter().enumerate() {
+            let separator = if i == 0 { ": " } else { ", " };
+            write!(f, "{separator}{}: {}", failure.method, failure.message)?;
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for AddressResolutionError {}
+impl AddressResolutionError {
+    pub fn to_json(&self) -> serde_json::Value {
+        let methods: Vec<serde_json::Value> = self
+            .failures
+            .iter()
+            .map(|failure| {
+                let mut method = json!({
+                    "method": failure.method.to_string(),
+                    "error": failure.message,
+                });
+                if let Some(data) = &failure.data {
+                    method
+                        .as_object_mut()
+                        .unwrap()
+                        .insert("data".to_owned(), data.clone());
+                }
+                method
+            })
+            .collect();
+        json!({"address": self.address, "methods": methods})
+    }
+}
@@ -0,0 +1,186 @@
+use aes::Aes256;
+use anyhow::anyhow;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use cbc::cipher::{BlockDecryptMut, KeyIvInit, block_padding::Pkcs7};
+use serde_json::json;
+
+use crate::structs::SuccessAction;
+
+const MAX_TEXT_LENGTH: usize = 144;
+const MAX_CIPHERTEXT_LENGTH: usize = 4096;
+const IV_LENGTH: usize = 24;
+
+// Unknown or malformed actions are dropped outside of strict mode, the invoice
+// can be paid without them
+pub fn parse_success_action(
+    callback: &str,
+    success_action: serde_json::Value,
+    strict_lnurl: bool,
+) -> Result<Option<SuccessAction>, anyhow::Error> {
+    let parsed = serde_json::from_value::<SuccessAction>(success_action)
+        .map_err(|e| anyhow!("unknown or malformed successAction: {e}"))
+        .and_then(|sa| validate_success_action(callback, &sa).map(|()| sa));
+    match parsed {
+        Ok(sa) => Ok(Some(sa)),
+        Err(e) => {
+            if strict_lnurl {
+                return Err(anyhow!("Strict mode: Lnurl: invalid successAction: {e}"));
+            }
+            log::warn!("Lnurl: ignoring invalid successAction: {e}");
+            Ok(None)
+        }
+    }
+}
+
+fn validate_success_action(
+    callback: &str,
+    success_action: &SuccessAction,
+) -> Result<(), anyhow::Error> {
+    match success_action {
+        SuccessAction::Message { message } => {
+            if message.chars().count() > MAX_TEXT_LENGTH {
+                return Err(anyhow!(
+                    "successAction message is longer than {MAX_TEXT_LENGTH} characters"
+                ));
+            }
+        }
+        SuccessAction::Url { description, url } => {
+            if description.chars().count() > MAX_TEXT_LENGTH {
+                return Err(anyhow!(
+                    "successAction description is longer than {MAX_TEXT_LENGTH} characters"
+                ));
+            }
+            let callback_url = url::Url::parse(callback)?;
+            let action_url = url::Url::parse(url)?;
+            let same_domain = match (callback_url.host_str(), action_url.host_str()) {
+                (Some(callback_host), Some(action_host)) => {
+                    callback_host.eq_ignore_ascii_case(action_host)
+                }
+                _ => false,
+            };
+            if !same_domain {
+                return Err(anyhow!(
+                    "successAction url is not on the callback's domain: {url}"
+                ));
+            }
+        }
+        SuccessAction::Aes {
+            description,
+            ciphertext,
+            iv,
+        } => {
+            if description.chars().count() > MAX_TEXT_LENGTH {
+                return Err(anyhow!(
+                    "successAction description is longer than {MAX_TEXT_LENGTH} characters"
+                ));
+            }
+            if ciphertext.len() > MAX_CIPHERTEXT_LENGTH {
+                return Err(anyhow!(
+                    "successAction ciphertext is longer than {MAX_CIPHERTEXT_LENGTH} bytes"
+                ));
+            }
+            if iv.len() != IV_LENGTH {
+                return Err(anyhow!(
+                    "successAction iv is not {IV_LENGTH} characters long"
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn decrypt_aes_success_action(
+    preimage: &[u8],
+    ciphertext: &str,
+    iv: &str,
+) -> Result<String, anyhow::Error> {
+    let iv_bytes = STANDARD.decode(iv)?;
+    let mut ciphertext_bytes = STANDARD.decode(ciphertext)?;
+    let decryptor = cbc::Decryptor::<Aes256>::new_from_slices(preimage, &iv_bytes)
+        .map_err(|e| anyhow!("invalid aes key or iv: {e}"))?;
+    let plaintext = decryptor
+        .decrypt_padded_mut::<Pkcs7>(&mut ciphertext_bytes)
+        .map_err(|e| anyhow!("could not decrypt successAction: {e}"))?;
+    Ok(String::from_utf8(plaintext.to_vec())?)
+}
+
+pub fn success_action_to_json(
+    success_action: &SuccessAction,
+    preimage: Option<&[u8]>,
+) -> serde_json::Value {
+    match success_action {
+        SuccessAction::Message { message } => json!({"tag":"message","message":message}),
+        SuccessAction::Url { description, url } => {
+            json!({"tag":"url","description":description,"url":url})
+        }
+        SuccessAction::Aes {
+            description,
+            ciphertext,
+            iv,
+        } => {
+            let Some(preimage) = preimage else {
+                return json!({"tag":"aes","description":description});
+            };
+            match decrypt_aes_success_action(preimage, ciphertext, iv) {
+                Ok(plaintext) => {
+                    json!({"tag":"aes","description":description,"plaintext":plaintext})
+                }
+                Err(e) => json!({"tag":"aes","description":description,"error":e.to_string()}),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_decrypt_aes_success_action() {
+    use cbc::cipher::BlockEncryptMut;
+
+    let preimage = [7u8; 32];
+    let iv = [3u8; 16];
+    let ciphertext = cbc::Encryptor::<Aes256>::new(&preimage.into(), &iv.into())
+        .encrypt_padded_vec_mut::<Pkcs7>(b"voucher: 1234-5678");
+    let result = decrypt_aes_success_action(
+        &preimage,
+        &STANDARD.encode(ciphertext),
+        &STANDARD.encode(iv),
+    )
+    .unwrap();
+    assert_eq!(result, "voucher: 1234-5678");
+    assert!(
+        decrypt_aes_success_action(
+            &[8u8; 32],
+            &STANDARD.encode([0u8; 16]),
+            &STANDARD.encode(iv)
+        )
+        .is_err()
+    );
+}
+
+#[test]
+fn test_parse_success_action() {
+    let callback = "https://site.com/cb";
+    assert!(matches!(
+        parse_success_action(callback, json!({"tag":"message","message":"thanks"}), true),
+        Ok(Some(SuccessAction::Message { .. }))
+    ));
+    let unknown = json!({"tag":"confetti","color":"gold"});
+    assert!(
+        parse_success_action(callback, unknown.clone(), false)
+            .unwrap()
+            .is_none()
+    );
+    assert!(parse_success_action(callback, unknown, true).is_err());
+    let malformed = json!({"tag":"url","url":"https://site.com/thanks"});
+    assert!(
+        parse_success_action(callback, malformed.clone(), false)
+            .unwrap()
+            .is_none()
+    );
+    assert!(parse_success_action(callback, malformed, true).is_err());
+    let other_domain = json!({"tag":"url","description":"d","url":"https://evil.com"});
+    assert!(
+        parse_success_action(callback, other_domain, false)
+            .unwrap()
+            .is_none()
+    );
+}
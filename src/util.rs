@@ -2,10 +2,17 @@ use std::path::Path;
 
 use anyhow::anyhow;
 use cln_plugin::Plugin;
-use cln_rpc::{model::requests::SetconfigRequest, ClnRpc};
+use cln_rpc::{
+    ClnRpc,
+    model::{
+        requests::{DecodeRequest, ListsendpaysRequest, SetconfigRequest},
+        responses::{ListsendpaysPayments, ListsendpaysPaymentsStatus},
+    },
+    primitives::Sha256,
+};
 use serde_json::json;
 
-use crate::PluginState;
+use crate::{PluginState, datastore::load_lnurl_record};
 
 pub async fn check_handle_option(plugin: Plugin<PluginState>) -> Result<(), anyhow::Error> {
     let mut rpc = ClnRpc::new(
@@ -51,6 +58,99 @@ pub async fn check_handle_option(plugin: Plugin<PluginState>) -> Result<(), anyh
     Ok(())
 }
 
+// Summarizes all sendpay attempts for a payment hash into complete, pending,
+// failed or unknown (never tried)
+pub async fn local_payment_status(
+    rpc: &mut ClnRpc,
+    payment_hash: Sha256,
+) -> Result<(&'static str, Vec<ListsendpaysPayments>), anyhow::Error> {
+    let payments = rpc
+        .call_typed(&ListsendpaysRequest {
+            bolt11: None,
+            index: None,
+            limit: None,
+            payment_hash: Some(payment_hash),
+            start: None,
+            status: None,
+        })
+        .await?
+        .payments;
+    let status = if payments
+        .iter()
+        .any(|p| p.status == ListsendpaysPaymentsStatus::COMPLETE)
+    {
+        "complete"
+    } else if payments
+        .iter()
+        .any(|p| p.status == ListsendpaysPaymentsStatus::PENDING)
+    {
+        "pending"
+    } else if payments.is_empty() {
+        "unknown"
+    } else {
+        "failed"
+    };
+    Ok((status, payments))
+}
+
+// The payment hash, its status and the payments of an invoice that was paid before
+pub type EarlierPayment = (Sha256, &'static str, Vec<ListsendpaysPayments>);
+
+// An invoice whose payment hash is already complete or pending must not be paid
+// a second time, returns the status and the earlier payments if so
+pub async fn earlier_payment(
+    plugin: Plugin<PluginState>,
+    rpc: &mut ClnRpc,
+    invstring: &str,
+) -> Result<Option<EarlierPayment>, anyhow::Error> {
+    let Some(payment_hash) = invoice_payment_hash(rpc, invstring).await? else {
+        return Ok(None);
+    };
+    earlier_payment_by_hash(plugin, rpc, payment_hash).await
+}
+
+// Stored LNURL invoices (LUD-11) that were settled count as paid even when
+// their payments are gone from listsendpays
+pub async fn earlier_payment_by_hash(
+    plugin: Plugin<PluginState>,
+    rpc: &mut ClnRpc,
+    payment_hash: Sha256,
+) -> Result<Option<EarlierPayment>, anyhow::Error> {
+    let (status, payments) = local_payment_status(rpc, payment_hash).await?;
+    match status {
+        "complete" | "pending" => Ok(Some((payment_hash, status, payments))),
+        "unknown" => {
+            let settled = load_lnurl_record(plugin, &payment_hash)
+                .await?
+                .is_some_and(|(record, _generation)| record.settled);
+            Ok(settled.then_some((payment_hash, "complete", payments)))
+        }
+        _ => Ok(None),
+    }
+}
+
+// Offers and strings that don't decode have no payment hash
+pub async fn invoice_payment_hash(
+    rpc: &mut ClnRpc,
+    invstring: &str,
+) -> Result<Option<Sha256>, anyhow::Error> {
+    let Ok(decoded) = rpc
+        .call_typed(&DecodeRequest {
+            string: invstring.to_owned(),
+        })
+        .await
+    else {
+        return Ok(None);
+    };
+    if let Some(ph) = decoded.payment_hash {
+        Ok(Some(ph))
+    } else if let Some(ph) = decoded.invoice_payment_hash {
+        Ok(Some(ph.parse()?))
+    } else {
+        Ok(None)
+    }
+}
+
 pub fn at_or_above_version(my_version: &str, min_version: &str) -> Result<bool, anyhow::Error> {
     let clean_start_my_version = my_version
         .split_once('v')
@@ -78,3 +178,23 @@ pub fn at_or_above_version(my_version: &str, min_version: &str) -> Result<bool,
 
     Ok(my_version_parts.len() >= min_version_parts.len())
 }
+
+pub fn strip_prefix_ignore_ascii_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = s.get(..prefix.len())?;
+    if head.eq_ignore_ascii_case(prefix) {
+        Some(&s[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+pub fn check_strict(strict_lnurl: bool, problem: &str) -> Result<(), anyhow::Error> {
+    if strict_lnurl {
+        return Err(anyhow!("Strict mode: Lnurl: {problem}"));
+    }
+    log::info!(
+        "Lnurl: {problem}, please report to lnaddress service provider \
+        they are violating the spec in LUD-06"
+    );
+    Ok(())
+}
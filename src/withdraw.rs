@@ -0,0 +1,131 @@
+use std::path::Path;
+
+use anyhow::{Context, Error, anyhow};
+use chrono::Utc;
+use cln_plugin::Plugin;
+use cln_rpc::{
+    ClnRpc,
+    model::requests::{DelinvoiceRequest, DelinvoiceStatus, InvoiceRequest},
+    primitives::{Amount, AmountOrAny},
+};
+use serde_json::json;
+
+use crate::{
+    lnurl::{build_client, fetch_lnurl_json, lnurl_to_url, parse_callback_url},
+    structs::{LnurlTag, LnurlwConfig, PluginState},
+};
+
+pub async fn withdraw_lnurl(
+    plugin: Plugin<PluginState>,
+    lnurl: &str,
+    amount_msat: Option<Amount>,
+    label: Option<String>,
+) -> Result<serde_json::Value, Error> {
+    let (withdraw_url, tag) = lnurl_to_url(lnurl)?;
+    if let Some(t) = tag {
+        if t != LnurlTag::WithdrawRequest {
+            return Err(anyhow!("LNURL is a {t} and not a withdrawRequest: {lnurl}"));
+        }
+    }
+
+    let config = plugin.state().config.lock().clone();
+    let client = build_client(&config)?;
+
+    let lnurlw_config: LnurlwConfig =
+        serde_json::from_value(fetch_lnurl_json(&client, &withdraw_url, "withdraw config").await?)
+            .context("Not a valid LNURL withdraw response")?;
+    log::debug!("lnurlw config: {lnurlw_config:?}");
+
+    let amount_msat = validate_lnurlw_config(&lnurlw_config, amount_msat)?;
+
+    let mut rpc = ClnRpc::new(
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file),
+    )
+    .await?;
+
+    let label = label.unwrap_or_else(|| {
+        format!(
+            "payany-withdraw-{}-{}",
+            Utc::now().timestamp_millis(),
+            lnurlw_config.k1
+        )
+    });
+    let invoice = rpc
+        .call_typed(&InvoiceRequest {
+            cltv: None,
+            deschashonly: None,
+            expiry: None,
+            preimage: None,
+            exposeprivatechannels: None,
+            fallbacks: None,
+            amount_msat: AmountOrAny::Amount(amount_msat),
+            description: lnurlw_config.default_description.clone(),
+            label: label.clone(),
+        })
+        .await?;
+
+    let mut callback_url = parse_callback_url(&lnurlw_config.callback)?;
+    callback_url
+        .query_pairs_mut()
+        .append_pair("k1", &lnurlw_config.k1)
+        .append_pair("pr", &invoice.bolt11);
+
+    if let Err(e) = fetch_lnurl_json(&client, callback_url.as_str(), "withdraw callback").await {
+        if let Err(del_err) = rpc
+            .call_typed(&DelinvoiceRequest {
+                desconly: None,
+                status: DelinvoiceStatus::UNPAID,
+                label: label.clone(),
+            })
+            .await
+        {
+            log::warn!("Could not delete invoice {label} of failed withdraw: {del_err}");
+        }
+        return Err(e);
+    }
+
+    Ok(json!({
+        "label": label,
+        "bolt11": invoice.bolt11,
+        "payment_hash": invoice.payment_hash.to_string(),
+        "amount_msat": amount_msat.msat(),
+        "description": lnurlw_config.default_description,
+        "expires_at": invoice.expires_at,
+    }))
+}
+
+fn validate_lnurlw_config(
+    lnurlw_config: &LnurlwConfig,
+    amount_msat: Option<Amount>,
+) -> Result<Amount, Error> {
+    if !lnurlw_config.tag.eq_ignore_ascii_case("withdrawRequest") {
+        return Err(anyhow!(
+            "LNURL config is not for a withdrawRequest: {}",
+            lnurlw_config.tag
+        ));
+    }
+    if lnurlw_config.min_withdrawable > lnurlw_config.max_withdrawable {
+        return Err(anyhow!(
+            "LNURL withdraw minimum is above maximum! {}>{}",
+            lnurlw_config.min_withdrawable,
+            lnurlw_config.max_withdrawable
+        ));
+    }
+    let amount_msat =
+        amount_msat.unwrap_or_else(|| Amount::from_msat(lnurlw_config.max_withdrawable));
+    if amount_msat.msat() < lnurlw_config.min_withdrawable {
+        return Err(anyhow!(
+            "Amount is below minimum withdrawable! {}<{}",
+            amount_msat.msat(),
+            lnurlw_config.min_withdrawable
+        ));
+    }
+    if amount_msat.msat() > lnurlw_config.max_withdrawable {
+        return Err(anyhow!(
+            "Amount is above maximum withdrawable! {}>{}",
+            amount_msat.msat(),
+            lnurlw_config.max_withdrawable
+        ));
+    }
+    Ok(amount_msat)
+}
@@ -7,6 +7,13 @@ from aiohttp import web
 from pyln.proto.bech32 import bech32_encode, convertbits
 import pytest_asyncio
 import asyncio
+import base64
+import os
+from cryptography.exceptions import InvalidSignature
+from cryptography.hazmat.primitives import hashes, padding
+from cryptography.hazmat.primitives.asymmetric import ec
+from cryptography.hazmat.primitives.asymmetric.utils import Prehashed
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
 
 
 def get_cln_version():
@@ -76,9 +83,12 @@ async def lnurl_server(node_factory):
     PORT = node_factory.get_unused_port()
 
     BASE = f"http://{HOST}:{PORT}"
+    PNG_BASE64 = base64.b64encode(b"\x89PNG\r\n\x1a\n").decode()
+
+    received_comments = {}
 
     async def pay_params(request):
-        callback = f"{BASE}/lnurl/callback"
+        callback = f"{BASE}/lnurl/callback?session=pytest"
 
         return web.json_response(
             {
@@ -86,12 +96,20 @@ async def lnurl_server(node_factory):
                 "commentAllowed": 256,
                 "minSendable": 1000,
                 "maxSendable": 1_000_000,
-                "metadata": json.dumps([["text/plain", "pytest lnurl server"]]),
+                "metadata": json.dumps(
+                    [
+                        ["text/plain", "pytest lnurl server"],
+                        ["text/long-desc", "lnurl server for the payany tests"],
+                        ["image/png;base64", PNG_BASE64],
+                    ]
+                ),
                 "tag": "payRequest",
             }
         )
 
     async def pay_callback(request):
+        if request.query.get("session") != "pytest":
+            return web.json_response({"status": "ERROR", "reason": "Lost session"})
         amount_msat = int(request.query["amount"])
 
         invoice_args = {
@@ -101,9 +119,8 @@ async def lnurl_server(node_factory):
 
         comment = request.query.get("comment")
         if comment is not None:
-            invoice_args["description"] = request.query["comment"]
-        else:
-            invoice_args["description"] = "pytest lnurl server"
+            received_comments[amount_msat] = comment
+        invoice_args["description"] = "pytest lnurl server"
 
         inv = node.rpc.call("invoice", invoice_args)
 
@@ -111,11 +128,342 @@ async def lnurl_server(node_factory):
             {
                 "pr": inv["bolt11"],
                 "routes": [],
+                "verify": f"{BASE}/lnurl/verify/{inv['payment_hash']}",
+            }
+        )
+
+    async def verify(request):
+        invoices = node.rpc.call(
+            "listinvoices", {"payment_hash": request.match_info["payment_hash"]}
+        )["invoices"]
+        if not invoices:
+            return web.json_response({"status": "ERROR", "reason": "Not found"})
+        invoice = invoices[0]
+        settled = invoice["status"] == "paid"
+
+        return web.json_response(
+            {
+                "status": "OK",
+                "settled": settled,
+                "preimage": invoice["payment_preimage"] if settled else None,
+                "pr": invoice["bolt11"],
             }
         )
 
+    async def aes_pay_params(request):
+        return web.json_response(
+            {
+                "callback": f"{BASE}/lnurl/callback/aes",
+                "minSendable": 1000,
+                "maxSendable": 1_000_000,
+                "metadata": json.dumps([["text/plain", "pytest lnurl server"]]),
+                "tag": "payRequest",
+            }
+        )
+
+    async def aes_pay_callback(request):
+        amount_msat = int(request.query["amount"])
+        preimage = os.urandom(32)
+
+        inv = node.rpc.call(
+            "invoice",
+            {
+                "amount_msat": amount_msat,
+                "label": f"aes-{amount_msat}",
+                "description": "pytest lnurl server",
+                "preimage": preimage.hex(),
+            },
+        )
+
+        iv = os.urandom(16)
+        padder = padding.PKCS7(128).padder()
+        padded = padder.update(b"voucher-1234") + padder.finalize()
+        encryptor = Cipher(algorithms.AES(preimage), modes.CBC(iv)).encryptor()
+        ciphertext = encryptor.update(padded) + encryptor.finalize()
+
+        return web.json_response(
+            {
+                "pr": inv["bolt11"],
+                "routes": [],
+                "successAction": {
+                    "tag": "aes",
+                    "description": "your voucher",
+                    "ciphertext": base64.b64encode(ciphertext).decode(),
+                    "iv": base64.b64encode(iv).decode(),
+                },
+            }
+        )
+
+    payerdata_metadata = json.dumps([["text/plain", "pytest payerdata"]])
+    received_payerdata = []
+
+    async def payerdata_pay_params(request):
+        return web.json_response(
+            {
+                "callback": f"{BASE}/lnurl/callback/payerdata",
+                "minSendable": 1000,
+                "maxSendable": 1_000_000,
+                "metadata": payerdata_metadata,
+                "tag": "payRequest",
+                "payerData": {
+                    "name": {"mandatory": True},
+                    "pubkey": {"mandatory": False},
+                    "auth": {"mandatory": False, "k1": os.urandom(32).hex()},
+                },
+            }
+        )
+
+    async def payerdata_pay_callback(request):
+        amount_msat = int(request.query["amount"])
+        payerdata = request.query["payerdata"]
+        received_payerdata.append(json.loads(payerdata))
+
+        inv = node.rpc.call(
+            "invoice",
+            {
+                "amount_msat": amount_msat,
+                "label": f"payerdata-{amount_msat}",
+                "description": payerdata_metadata + payerdata,
+                "deschashonly": True,
+            },
+        )
+
+        return web.json_response({"pr": inv["bolt11"], "routes": []})
+
+    withdraw_k1 = os.urandom(32).hex()
+
+    async def withdraw_params(request):
+        return web.json_response(
+            {
+                "tag": "withdrawRequest",
+                "callback": f"{BASE}/lnurl/withdraw/callback?session=abc",
+                "k1": withdraw_k1,
+                "defaultDescription": "pytest withdraw",
+                "minWithdrawable": 1000,
+                "maxWithdrawable": 200_000,
+            }
+        )
+
+    async def withdraw_callback(request):
+        if request.query.get("session") != "abc":
+            return web.json_response({"status": "ERROR", "reason": "Lost session"})
+        if request.query.get("k1") != withdraw_k1:
+            return web.json_response({"status": "ERROR", "reason": "Wrong k1"})
+        threading.Thread(
+            target=node.rpc.call,
+            args=("xpay", [request.query["pr"]]),
+            daemon=True,
+        ).start()
+
+        return web.json_response({"status": "OK"})
+
+    auth_k1 = os.urandom(32).hex()
+    auth_logins = []
+
+    async def auth(request):
+        if request.query.get("k1") != auth_k1:
+            return web.json_response({"status": "ERROR", "reason": "Unknown k1"})
+        try:
+            pubkey = ec.EllipticCurvePublicKey.from_encoded_point(
+                ec.SECP256K1(), bytes.fromhex(request.query["key"])
+            )
+            pubkey.verify(
+                bytes.fromhex(request.query["sig"]),
+                bytes.fromhex(auth_k1),
+                ec.ECDSA(Prehashed(hashes.SHA256())),
+            )
+        except (InvalidSignature, KeyError, ValueError):
+            return web.json_response({"status": "ERROR", "reason": "Bad signature"})
+        auth_logins.append(request.query["key"])
+
+        return web.json_response({"status": "OK"})
+
+    channel_k1 = os.urandom(32).hex()
+    channel_requests = []
+
+    async def channel_params(request):
+        return web.json_response(
+            {
+                "tag": "channelRequest",
+                "uri": f"{node.info['id']}@localhost:{node.port}",
+                "callback": f"{BASE}/lnurl/channel/callback",
+                "k1": channel_k1,
+            }
+        )
+
+    async def channel_callback(request):
+        if request.query.get("k1") != channel_k1:
+            return web.json_response({"status": "ERROR", "reason": "Wrong k1"})
+        if request.query.get("remoteid") not in [
+            p["id"] for p in node.rpc.listpeers()["peers"]
+        ]:
+            return web.json_response({"status": "ERROR", "reason": "Not connected"})
+        channel_requests.append(dict(request.query))
+
+        return web.json_response({"status": "OK"})
+
+    reusable_invoices = {}
+
+    async def reusable_pay_params(request):
+        return web.json_response(
+            {
+                "callback": f"{BASE}/lnurl/callback/reusable",
+                "minSendable": 10_000,
+                "maxSendable": 10_000,
+                "metadata": json.dumps([["text/plain", "pytest reusable"]]),
+                "tag": "payRequest",
+            }
+        )
+
+    async def reusable_pay_callback(request):
+        if "bolt11" not in reusable_invoices:
+            inv = node.rpc.call(
+                "invoice",
+                {
+                    "amount_msat": int(request.query["amount"]),
+                    "label": "test-reusable",
+                    "description": json.dumps([["text/plain", "pytest reusable"]]),
+                    "deschashonly": True,
+                },
+            )
+            reusable_invoices["bolt11"] = inv["bolt11"]
+
+        return web.json_response(
+            {"pr": reusable_invoices["bolt11"], "routes": [], "disposable": False}
+        )
+
+    received_zaps = []
+
+    async def zap_pay_params(request):
+        return web.json_response(
+            {
+                "callback": f"{BASE}/lnurl/callback/zap",
+                "minSendable": 1000,
+                "maxSendable": 1_000_000,
+                "metadata": json.dumps([["text/plain", "pytest zaps"]]),
+                "tag": "payRequest",
+                "allowsNostr": True,
+                "nostrPubkey": "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            }
+        )
+
+    async def zap_pay_callback(request):
+        zap_request = request.query["nostr"]
+        received_zaps.append(json.loads(zap_request))
+        inv = node.rpc.call(
+            "invoice",
+            {
+                "amount_msat": int(request.query["amount"]),
+                "label": f"test-zap-{len(received_zaps)}",
+                "description": zap_request,
+                "deschashonly": True,
+            },
+        )
+
+        return web.json_response({"pr": inv["bolt11"], "routes": []})
+
+    async def error_pay_params(request):
+        return web.json_response(
+            {
+                "callback": f"{BASE}/lnurl/callback/error",
+                "minSendable": 1000,
+                "maxSendable": 1_000_000,
+                "metadata": json.dumps([["text/plain", "pytest errors"]]),
+                "tag": "payRequest",
+            }
+        )
+
+    async def error_pay_callback(request):
+        return web.json_response({"status": "ERROR", "reason": "Amount too small"})
+
+    async def lenient_pay_params(request):
+        return web.json_response(
+            {
+                "callback": f"{BASE}/lnurl/callback/lenient",
+                "minSendable": "1000",
+                "maxSendable": "1000000",
+                "metadata": json.dumps([["text/plain", "pytest lenient"]]),
+                "tag": "payrequest",
+            }
+        )
+
+    async def lenient_pay_callback(request):
+        inv = node.rpc.call(
+            "invoice",
+            {
+                "amount_msat": int(request.query["amount"]),
+                "label": f"lenient-{request.query['amount']}",
+                "description": json.dumps([["text/plain", "pytest lenient"]]),
+                "deschashonly": True,
+            },
+        )
+
+        return web.json_response(
+            {
+                "pr": inv["bolt11"],
+                "successAction": {"tag": "confetti", "color": "gold"},
+            }
+        )
+
+    refetch_calls = []
+
+    async def refetch_pay_params(request):
+        return web.json_response(
+            {
+                "callback": f"{BASE}/lnurl/callback/refetch",
+                "minSendable": 1000,
+                "maxSendable": 1_000_000,
+                "metadata": json.dumps([["text/plain", "pytest refetch"]]),
+                "tag": "payRequest",
+            }
+        )
+
+    async def refetch_pay_callback(request):
+        refetch_calls.append(request.query["amount"])
+        label = f"refetch-{len(refetch_calls)}"
+        inv = node.rpc.call(
+            "invoice",
+            {
+                "amount_msat": int(request.query["amount"]),
+                "label": label,
+                "description": json.dumps([["text/plain", "pytest refetch"]]),
+                "deschashonly": True,
+            },
+        )
+        # the first invoice is withdrawn by the service before it is paid
+        if len(refetch_calls) == 1:
+            node.rpc.call("delinvoice", {"label": label, "status": "unpaid"})
+
+        return web.json_response({"pr": inv["bolt11"], "routes": []})
+
+    async def unknown_user(request):
+        return web.json_response(
+            {"status": "ERROR", "reason": "User does not exist"}, status=404
+        )
+
     app.router.add_get("/.well-known/lnurlp/test", pay_params)
     app.router.add_get("/lnurl/callback", pay_callback)
+    app.router.add_get("/lnurl/verify/{payment_hash}", verify)
+    app.router.add_get("/.well-known/lnurlp/aes", aes_pay_params)
+    app.router.add_get("/lnurl/callback/aes", aes_pay_callback)
+    app.router.add_get("/.well-known/lnurlp/payerdata", payerdata_pay_params)
+    app.router.add_get("/lnurl/callback/payerdata", payerdata_pay_callback)
+    app.router.add_get("/lnurl/withdraw", withdraw_params)
+    app.router.add_get("/lnurl/withdraw/callback", withdraw_callback)
+    app.router.add_get("/lnurl/auth", auth)
+    app.router.add_get("/.well-known/lnurlp/zap", zap_pay_params)
+    app.router.add_get("/lnurl/callback/zap", zap_pay_callback)
+    app.router.add_get("/.well-known/lnurlp/reusable", reusable_pay_params)
+    app.router.add_get("/lnurl/callback/reusable", reusable_pay_callback)
+    app.router.add_get("/lnurl/channel", channel_params)
+    app.router.add_get("/lnurl/channel/callback", channel_callback)
+    app.router.add_get("/.well-known/lnurlp/error", error_pay_params)
+    app.router.add_get("/lnurl/callback/error", error_pay_callback)
+    app.router.add_get("/.well-known/lnurlp/unknown", unknown_user)
+    app.router.add_get("/.well-known/lnurlp/refetch", refetch_pay_params)
+    app.router.add_get("/lnurl/callback/refetch", refetch_pay_callback)
+    app.router.add_get("/.well-known/lnurlp/lenient", lenient_pay_params)
+    app.router.add_get("/lnurl/callback/lenient", lenient_pay_callback)
 
     thread = threading.Thread(
         target=run_app,
@@ -125,7 +473,41 @@ async def lnurl_server(node_factory):
     thread.start()
 
     lnurl = encode_lnurl(f"{BASE}/.well-known/lnurlp/test")
+    lnurl_aes = encode_lnurl(f"{BASE}/.well-known/lnurlp/aes")
+    lnurl_payerdata = encode_lnurl(f"{BASE}/.well-known/lnurlp/payerdata")
+    lnurl_withdraw = encode_lnurl(f"{BASE}/lnurl/withdraw")
+    lnurl_reusable = encode_lnurl(f"{BASE}/.well-known/lnurlp/reusable")
+    lnurl_zap = encode_lnurl(f"{BASE}/.well-known/lnurlp/zap")
+    lnurl_channel = encode_lnurl(f"{BASE}/lnurl/channel")
+    lnurl_error = encode_lnurl(f"{BASE}/.well-known/lnurlp/error")
+    lnurl_unknown = encode_lnurl(f"{BASE}/.well-known/lnurlp/unknown")
+    lnurl_refetch = encode_lnurl(f"{BASE}/.well-known/lnurlp/refetch")
+    lnurl_lenient = encode_lnurl(f"{BASE}/.well-known/lnurlp/lenient")
+    lnurl_auth = encode_lnurl(f"{BASE}/lnurl/auth?tag=login&k1={auth_k1}&action=login")
 
     await asyncio.sleep(1)
 
-    yield {"lnurl": lnurl, "node": node, "base": BASE}
+    yield {
+        "lnurl": lnurl,
+        "lnurl_aes": lnurl_aes,
+        "lnurl_payerdata": lnurl_payerdata,
+        "lnurl_withdraw": lnurl_withdraw,
+        "lnurl_auth": lnurl_auth,
+        "lnurl_channel": lnurl_channel,
+        "lnurl_error": lnurl_error,
+        "lnurl_unknown": lnurl_unknown,
+        "lnurl_refetch": lnurl_refetch,
+        "refetch_calls": refetch_calls,
+        "lnurl_lenient": lnurl_lenient,
+        "lnurl_zap": lnurl_zap,
+        "received_zaps": received_zaps,
+        "lnurl_reusable": lnurl_reusable,
+        "channel_requests": channel_requests,
+        "auth_k1": auth_k1,
+        "auth_logins": auth_logins,
+        "received_payerdata": received_payerdata,
+        "received_comments": received_comments,
+        "node": node,
+        "base": BASE,
+        "png_base64": PNG_BASE64,
+    }
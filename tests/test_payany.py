@@ -32,17 +32,83 @@ def test_payany_with_offer_and_bi353(node_factory, get_plugin):  # noqa: F811
             "message": "test1",
         },
     )
-    assert result["invoice"] == offer["bolt12"]
+    assert result["invoice"].startswith("lni")
+    assert result["offer"] == offer["bolt12"]
+    invoice = l1.rpc.decode(result["invoice"])
+    assert invoice["offer_id"] == offer["offer_id"]
+    assert invoice["invoice_amount_msat"] == 1_000
+    assert invoice["invreq_payer_note"] == "test1"
+    l1.rpc.call("xpay", {"invstring": result["invoice"]})
 
+    with pytest.raises(
+        RpcError, match="missing `amount_msat` for offer without amount"
+    ):
+        l1.rpc.call("payany", {"invstring": offer["bolt12"]})
+
+    fixed_offer = l2.rpc.call("offer", {"amount": 3_000, "description": "fixed"})
     result = l1.rpc.call(
-        "payany",
-        {
-            "invstring": "test@notalnurlserver.gz",
-            "amount_msat": 2_000,
-            "message": "test2",
-        },
+        "payany", {"invstring": fixed_offer["bolt12"], "amount_msat": 3_000}
     )
-    assert result["invoice"] == "test@notalnurlserver.gz"
+    assert l1.rpc.decode(result["invoice"])["invoice_amount_msat"] == 3_000
+    with pytest.raises(RpcError, match="offer is for 3000msat and not for 2000msat"):
+        l1.rpc.call(
+            "payany", {"invstring": fixed_offer["bolt12"], "amount_msat": 2_000}
+        )
+
+    with pytest.raises(
+        RpcError, match="could not resolve test@notalnurlserver.gz: lnurl: .*, bip353: "
+    ) as err:
+        l1.rpc.call(
+            "payany",
+            {
+                "invstring": "test@notalnurlserver.gz",
+                "amount_msat": 2_000,
+                "message": "test2",
+            },
+        )
+    assert err.value.error["data"]["address"] == "test@notalnurlserver.gz"
+    assert [m["method"] for m in err.value.error["data"]["methods"]] == [
+        "lnurl",
+        "bip353",
+    ]
+
+    l1.rpc.setconfig("payany-address-priority", "bip353")
+    with pytest.raises(RpcError, match="notalnurlserver.gz: bip353: ") as err:
+        l1.rpc.call(
+            "payany",
+            {"invstring": "test@notalnurlserver.gz", "amount_msat": 2_000},
+        )
+    assert len(err.value.error["data"]["methods"]) == 1
+
+    with pytest.raises(RpcError, match="valid are `lnurl`, `bip353` and `blip32`"):
+        l1.rpc.setconfig("payany-address-priority", "bip353,dns")
+
+    l1.rpc.setconfig("payany-address-method-grace", 0)
+    with pytest.raises(RpcError, match="needs to be a positive number"):
+        l1.rpc.setconfig("payany-address-method-grace", -1)
+    with pytest.raises(RpcError, match="is not an https url"):
+        l1.rpc.setconfig("payany-doh-servers", "http://dns.example.com/dns-query")
+
+    l1.rpc.setconfig("payany-address-priority", "blip32")
+    with pytest.raises(
+        RpcError, match="blip32: no dns resolver configured or found in gossip"
+    ):
+        l1.rpc.call(
+            "payany",
+            {"invstring": "test@notalnurlserver.gz", "amount_msat": 2_000},
+        )
+
+    # l2 gets the dnssec_query but is no resolver, so it never answers
+    l1.rpc.setconfig("payany-dns-resolvers", l2.info["id"])
+    l1.rpc.setconfig("payany-dns-resolver-timeout", 2)
+    with pytest.raises(RpcError, match="no dnssec_proof within 2s"):
+        l1.rpc.call(
+            "payany",
+            {"invstring": "test@notalnurlserver.gz", "amount_msat": 2_000},
+        )
+
+    with pytest.raises(RpcError, match="invalid node id `02ad`"):
+        l1.rpc.setconfig("payany-dns-resolvers", "02ad")
 
 
 def test_xpay_supercharged(node_factory, get_plugin, lnurl_server):  # noqa: F811
@@ -61,7 +127,8 @@ def test_xpay_supercharged(node_factory, get_plugin, lnurl_server):  # noqa: F81
     assert result["amount_msat"] == 3_000
     pay = l2.rpc.call("listinvoices", {})["invoices"]
     assert pay[0]["amount_msat"] == 3_000
-    assert pay[0]["description"] == "test3"
+    assert pay[0]["description"] == "pytest lnurl server"
+    assert lnurl_server["received_comments"][3_000] == "test3"
 
     result = l1.rpc.call("xpay", [lnurl, 2_000])
     assert result["amount_msat"] == 2_000
@@ -69,12 +136,578 @@ def test_xpay_supercharged(node_factory, get_plugin, lnurl_server):  # noqa: F81
     assert pay[1]["amount_msat"] == 2_000
     assert pay[1]["description"] == "pytest lnurl server"
 
+    # commentAllowed counts characters and the comment is form-urlencoded
+    message = "tea & cake #1 \u2615" + "x" * 241
+    result = l1.rpc.call(
+        "xpay", {"invstring": lnurl, "amount_msat": 4_000, "message": message}
+    )
+    assert result["amount_msat"] == 4_000
+    assert lnurl_server["received_comments"][4_000] == message
+
+    lud17 = lnurl_server["base"].replace("http://", "lnurlp://")
+    result = l1.rpc.call(
+        "xpay", {"invstring": f"{lud17}/.well-known/lnurlp/test", "amount_msat": 4_000}
+    )
+    assert result["amount_msat"] == 4_000
+
+    # LUD-01 fallback urls carry the LNURL in the `lightning` parameter
+    result = l1.rpc.call(
+        "xpay",
+        {"invstring": f"https://example.com/?lightning={lnurl}", "amount_msat": 5_000},
+    )
+    assert result["amount_msat"] == 5_000
+
+    with pytest.raises(RpcError, match="bech32 prefix must be `lnurl`"):
+        l1.rpc.call(
+            "xpay",
+            {
+                "invstring": "lnurlx1qqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqq",
+                "amount_msat": 4_000,
+            },
+        )
+
+    with pytest.raises(RpcError, match="withdrawRequest can not be paid"):
+        l1.rpc.call(
+            "xpay",
+            {"invstring": "lnurlw://example.com/withdraw", "amount_msat": 4_000},
+        )
+
     with pytest.raises(
         RpcError, match="missing required parameter: `invstring`/`bolt11`"
     ):
         result = l1.rpc.call("xpay", [])
 
 
+def test_success_action(node_factory, get_plugin, lnurl_server):  # noqa: F811
+    opts = {"plugin": get_plugin, "log-level": "debug"}
+
+    l1 = node_factory.get_node(
+        options=opts,
+    )
+    l2 = lnurl_server["node"]
+    l1.fundchannel(l2, 1_000_000, wait_for_active=True)
+
+    lnurl_aes = lnurl_server["lnurl_aes"]
+    result = l1.rpc.call("payany", {"invstring": lnurl_aes, "amount_msat": 3_000})
+    assert result["success_action"] == {"tag": "aes", "description": "your voucher"}
+
+    result = l1.rpc.call("xpay", {"invstring": lnurl_aes, "amount_msat": 4_000})
+    assert result["amount_msat"] == 4_000
+    l1.daemon.wait_for_log(r'LNURL successAction: .*"plaintext":"voucher-1234"')
+
+
+def test_payer_data(node_factory, get_plugin, lnurl_server):  # noqa: F811
+    opts = {"plugin": get_plugin, "log-level": "debug"}
+
+    l1 = node_factory.get_node(
+        options=opts,
+    )
+    l2 = lnurl_server["node"]
+    l1.fundchannel(l2, 1_000_000, wait_for_active=True)
+
+    lnurl_payerdata = lnurl_server["lnurl_payerdata"]
+    with pytest.raises(RpcError, match="service requires payerData `name`"):
+        l1.rpc.call("xpay", {"invstring": lnurl_payerdata, "amount_msat": 3_000})
+
+    result = l1.rpc.call(
+        "xpay",
+        {
+            "invstring": lnurl_payerdata,
+            "amount_msat": 3_000,
+            "payer_name": "satoshi",
+        },
+    )
+    assert result["amount_msat"] == 3_000
+    payerdata = lnurl_server["received_payerdata"][-1]
+    assert payerdata["name"] == "satoshi"
+    assert payerdata["auth"]["key"] == payerdata["pubkey"]
+
+    l1.rpc.call("setconfig", ["payany-payer-name", "hal"])
+    l1.rpc.call("xpay", {"invstring": lnurl_payerdata, "amount_msat": 4_000})
+    assert lnurl_server["received_payerdata"][-1]["name"] == "hal"
+    assert lnurl_server["received_payerdata"][-1]["pubkey"] == payerdata["pubkey"]
+
+
+def test_verify(node_factory, get_plugin, lnurl_server):  # noqa: F811
+    opts = {"plugin": get_plugin, "log-level": "debug"}
+
+    l1 = node_factory.get_node(
+        options=opts,
+    )
+    l2 = lnurl_server["node"]
+    l1.fundchannel(l2, 1_000_000, wait_for_active=True)
+
+    result = l1.rpc.call(
+        "payany", {"invstring": lnurl_server["lnurl"], "amount_msat": 5_000}
+    )
+    assert result["verify"].startswith(lnurl_server["base"])
+    assert result["metadata"] == {
+        "description": "pytest lnurl server",
+        "long_description": "lnurl server for the payany tests",
+        "image": {"mime_type": "image/png", "base64": lnurl_server["png_base64"]},
+    }
+    payment_hash = l1.rpc.call("decode", [result["invoice"]])["payment_hash"]
+
+    verify = l1.rpc.call("payany-verify", [payment_hash])
+    assert verify["settled"] is False
+    assert verify["local_status"] == "unknown"
+
+    l1.rpc.call("xpay", [result["invoice"]])
+    verify = l1.rpc.call("payany-verify", {"payment_hash": payment_hash})
+    assert verify["settled"] is True
+    assert verify["local_status"] == "complete"
+    assert verify["preimage_valid"] is True
+    assert verify["preimage_matches"] is True
+
+    with pytest.raises(RpcError, match="no LNURL invoice known for payment hash"):
+        l1.rpc.call("payany-verify", ["00" * 32])
+
+
+def test_non_disposable(node_factory, get_plugin, lnurl_server):  # noqa: F811
+    l1 = node_factory.get_node(options={"plugin": get_plugin, "log-level": "debug"})
+    l2 = lnurl_server["node"]
+    l1.fundchannel(l2, 1_000_000, wait_for_active=True)
+
+    result = l1.rpc.call(
+        "payany", {"invstring": lnurl_server["lnurl_reusable"], "amount_msat": 10_000}
+    )
+    l1.daemon.wait_for_log("invoice is not disposable, storing it")
+    l1.rpc.call("xpay", [result["invoice"]])
+
+    with pytest.raises(RpcError, match="invoice .* was already paid"):
+        l1.rpc.call("xpay", [result["invoice"]])
+    # the service hands out the same invoice again
+    with pytest.raises(RpcError, match="invoice .* was already paid"):
+        l1.rpc.call(
+            "xpay",
+            {"invstring": lnurl_server["lnurl_reusable"], "amount_msat": 10_000},
+        )
+
+
+def test_lnurl_errors(node_factory, get_plugin, lnurl_server):  # noqa: F811
+    l1 = node_factory.get_node(options={"plugin": get_plugin, "log-level": "debug"})
+
+    with pytest.raises(RpcError, match="User does not exist") as err:
+        l1.rpc.call(
+            "payany", {"invstring": lnurl_server["lnurl_unknown"], "amount_msat": 3_000}
+        )
+    assert err.value.error["data"] == {
+        "lnurl_request": "lnurl config",
+        "http_status": 404,
+        "reason": "User does not exist",
+    }
+
+    with pytest.raises(RpcError, match="Amount too small") as err:
+        l1.rpc.call(
+            "xpay", {"invstring": lnurl_server["lnurl_error"], "amount_msat": 3_000}
+        )
+    assert err.value.error["data"] == {
+        "lnurl_request": "invoice",
+        "reason": "Amount too small",
+    }
+
+
+def test_lenient_lnurl(node_factory, get_plugin, lnurl_server):  # noqa: F811
+    l1 = node_factory.get_node(options={"plugin": get_plugin, "log-level": "debug"})
+    l2 = lnurl_server["node"]
+    l1.fundchannel(l2, 1_000_000, wait_for_active=True)
+
+    result = l1.rpc.call(
+        "xpay", {"invstring": lnurl_server["lnurl_lenient"], "amount_msat": 3_000}
+    )
+    assert result["amount_msat"] == 3_000
+    l1.daemon.wait_for_logs(
+        [
+            r"config `minSendable` is not a json integer",
+            r"config `tag` is `payrequest` instead of `payRequest`",
+            r"invoice response is missing `routes`",
+            r"ignoring invalid successAction: unknown or malformed successAction",
+        ]
+    )
+
+    l1.rpc.setconfig("payany-strict-lnurl", True)
+    with pytest.raises(
+        RpcError, match="Strict mode: Lnurl: config `minSendable` is not a json integer"
+    ):
+        l1.rpc.call(
+            "xpay", {"invstring": lnurl_server["lnurl_lenient"], "amount_msat": 3_000}
+        )
+
+
+def test_quote(node_factory, get_plugin, lnurl_server):  # noqa: F811
+    l1 = node_factory.get_node(options={"plugin": get_plugin, "log-level": "debug"})
+    l2 = lnurl_server["node"]
+    l1.fundchannel(l2, 1_000_000, wait_for_active=True)
+
+    quote = l1.rpc.call("payany", {"invstring": lnurl_server["lnurl"], "quote": True})
+    assert quote["min_sendable_msat"] == 1_000
+    assert quote["max_sendable_msat"] == 1_000_000
+    assert quote["comment_allowed"] == 256
+    assert quote["metadata"]["description"] == "pytest lnurl server"
+    assert not l2.rpc.call("listinvoices", {})["invoices"]
+
+    with pytest.raises(RpcError, match="missing amount_msat, service accepts"):
+        l1.rpc.call("payany", {"quote_id": quote["quote_id"]})
+    result = l1.rpc.call(
+        "payany",
+        {"quote_id": quote["quote_id"], "amount_msat": 7_000, "message": "quoted"},
+    )
+    assert l1.rpc.call("decode", [result["invoice"]])["amount_msat"] == 7_000
+    assert lnurl_server["received_comments"][7_000] == "quoted"
+
+    with pytest.raises(RpcError, match="unknown or expired quote_id"):
+        l1.rpc.call("payany", {"quote_id": "00" * 32, "amount_msat": 7_000})
+
+    # fixed amount services don't need amount_msat
+    result = l1.rpc.call("xpay", [lnurl_server["lnurl_reusable"]])
+    assert result["amount_msat"] == 10_000
+
+
+def test_lnurl_refetch(node_factory, get_plugin, lnurl_server):  # noqa: F811
+    l1 = node_factory.get_node(
+        options={
+            "plugin": get_plugin,
+            "log-level": "debug",
+            "payany-lnurl-refetch": 1,
+        }
+    )
+    l2 = lnurl_server["node"]
+    l1.fundchannel(l2, 1_000_000, wait_for_active=True)
+
+    result = l1.rpc.call(
+        "xpay", {"invstring": lnurl_server["lnurl_refetch"], "amount_msat": 3_000}
+    )
+    assert result["amount_msat"] == 3_000
+    assert len(lnurl_server["refetch_calls"]) == 2
+    l1.daemon.wait_for_log(r"fetching a new invoice \(1/1\)")
+
+
+def test_address_fallback(node_factory, get_plugin, lnurl_server):  # noqa: F811
+    # l1 has no channels, so the LNURL invoice is unroutable
+    l1 = node_factory.get_node(
+        options={
+            "plugin": get_plugin,
+            "log-level": "debug",
+            "payany-address-fallback": True,
+        }
+    )
+    address = "test@" + lnurl_server["base"].removeprefix("http://")
+
+    with pytest.raises(RpcError) as err:
+        l1.rpc.call("xpay", {"invstring": address, "amount_msat": 5_000})
+    attempts = err.value.error["data"]["attempts"]
+    assert len(attempts) == 1
+    assert attempts[0]["method"] == "lnurl"
+    assert attempts[0]["invstring"].startswith("lnbcrt")
+    assert "error" in attempts[0]
+    # nothing of the failed invoice is in flight when falling back
+    assert all(
+        p["status"] == "failed" for p in l1.rpc.listsendpays()["payments"]
+    )
+
+
+def test_repeat_payment(node_factory, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.line_graph(
+        2,
+        wait_for_announce=True,
+        opts=[{"plugin": get_plugin, "log-level": "debug"}, {"log-level": "debug"}],
+    )
+
+    invoice = l2.rpc.call("invoice", [10_000, "repeat", "repeat"])
+    l1.rpc.call("xpay", [invoice["bolt11"]])
+
+    with pytest.raises(RpcError, match="invoice .* was already paid") as err:
+        l1.rpc.call("xpay", [invoice["bolt11"]])
+    assert err.value.error["data"]["payment_hash"] == invoice["payment_hash"]
+    assert err.value.error["data"]["status"] == "complete"
+    assert err.value.error["data"]["payments"][0]["status"] == "complete"
+
+
+def test_idempotency_key(node_factory, get_plugin, lnurl_server):  # noqa: F811
+    l1 = node_factory.get_node(options={"plugin": get_plugin, "log-level": "debug"})
+    l2 = lnurl_server["node"]
+    l1.fundchannel(l2, 1_000_000, wait_for_active=True)
+
+    fetched = l1.rpc.call(
+        "payany",
+        {
+            "invstring": lnurl_server["lnurl"],
+            "amount_msat": 4_000,
+            "idempotency_key": "job-1",
+        },
+    )
+    assert fetched["idempotency_key"] == "job-1"
+    refetched = l1.rpc.call(
+        "payany",
+        {
+            "invstring": lnurl_server["lnurl"],
+            "amount_msat": 4_000,
+            "idempotency_key": "job-1",
+        },
+    )
+    assert refetched["invoice"] == fetched["invoice"]
+    assert refetched["status"] == "unknown"
+
+    result = l1.rpc.call(
+        "xpay",
+        {
+            "invstring": lnurl_server["lnurl"],
+            "amount_msat": 4_000,
+            "idempotency_key": "job-1",
+        },
+    )
+    assert result["amount_msat"] == 4_000
+
+    retried = l1.rpc.call(
+        "xpay",
+        {
+            "invstring": lnurl_server["lnurl"],
+            "amount_msat": 4_000,
+            "idempotency_key": "job-1",
+        },
+    )
+    assert retried["status"] == "complete"
+    assert retried["invoice"] == fetched["invoice"]
+    assert retried["payment_preimage"] == result["payment_preimage"]
+    assert retried["amount_msat"] == 4_000
+    assert len(l1.rpc.listsendpays()["payments"]) == result["successful_parts"]
+
+    l1.rpc.setconfig("payany-nostr-key", "01" * 32)
+    zap_params = {
+        "invstring": lnurl_server["lnurl_zap"],
+        "amount_msat": 21_000,
+        "zap_pubkey": "c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+        "zap_relays": ["wss://relay.example.com"],
+        "idempotency_key": "zap-1",
+    }
+    fetched_zap = l1.rpc.call("payany", zap_params)
+    zaps = len(lnurl_server["received_zaps"])
+    zap_result = l1.rpc.call("xpay", zap_params)
+    assert zap_result["amount_msat"] == 21_000
+    assert len(lnurl_server["received_zaps"]) == zaps
+    zap_payments = l1.rpc.listsendpays(bolt11=fetched_zap["invoice"])["payments"]
+    assert zap_payments[0]["status"] == "complete"
+
+    with pytest.raises(RpcError, match="must be a string"):
+        l1.rpc.call(
+            "xpay",
+            {
+                "invstring": lnurl_server["lnurl"],
+                "amount_msat": 4_000,
+                "idempotency_key": 1,
+            },
+        )
+
+
+def test_idempotency_key_offer(node_factory, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.line_graph(
+        2,
+        wait_for_announce=True,
+        opts=[
+            {
+                "plugin": get_plugin,
+                "log-level": "debug",
+                "payany-budget-per": "5 hours",
+                "payany-budget-amount-msat": 10_000,
+            },
+            {"log-level": "debug"},
+        ],
+    )
+    offer = l2.rpc.call("offer", {"amount": "any", "description": "idempotent"})
+
+    # the key is only stored once the budget allows the payment
+    with pytest.raises(RpcError, match="payany budget exceeded"):
+        l1.rpc.call(
+            "xpay",
+            {
+                "invstring": offer["bolt12"],
+                "amount_msat": 20_000,
+                "idempotency_key": "offer-1",
+            },
+        )
+    assert (
+        l1.rpc.listdatastore(["payany", "idempotency", "offer-1"])["datastore"] == []
+    )
+
+    # offers are stored as the bolt12 invoice fetched for them
+    result = l1.rpc.call(
+        "xpay",
+        {
+            "invstring": offer["bolt12"],
+            "amount_msat": 5_000,
+            "idempotency_key": "offer-1",
+        },
+    )
+    assert result["amount_msat"] == 5_000
+    retried = l1.rpc.call(
+        "xpay",
+        {
+            "invstring": offer["bolt12"],
+            "amount_msat": 5_000,
+            "idempotency_key": "offer-1",
+        },
+    )
+    assert retried["status"] == "complete"
+    assert retried["invoice"].startswith("lni")
+    assert retried["payment_preimage"] == result["payment_preimage"]
+    assert len(l1.rpc.listsendpays()["payments"]) == result["successful_parts"]
+
+
+def test_zap(node_factory, get_plugin, lnurl_server):  # noqa: F811
+    l1 = node_factory.get_node(options={"plugin": get_plugin, "log-level": "debug"})
+    l2 = lnurl_server["node"]
+    l1.fundchannel(l2, 1_000_000, wait_for_active=True)
+    recipient = "c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5"
+
+    with pytest.raises(RpcError, match="payany-nostr-key. is not set"):
+        l1.rpc.call(
+            "payany",
+            {
+                "invstring": lnurl_server["lnurl_zap"],
+                "amount_msat": 21_000,
+                "zap_pubkey": recipient,
+                "zap_relays": ["wss://relay.example.com"],
+            },
+        )
+    l1.rpc.setconfig("payany-nostr-key", "01" * 32)
+
+    with pytest.raises(RpcError, match="missing `zap_relays`"):
+        l1.rpc.call(
+            "payany",
+            {
+                "invstring": lnurl_server["lnurl_zap"],
+                "amount_msat": 21_000,
+                "zap_pubkey": recipient,
+            },
+        )
+    with pytest.raises(RpcError, match="does not allow nostr zaps"):
+        l1.rpc.call(
+            "payany",
+            {
+                "invstring": lnurl_server["lnurl"],
+                "amount_msat": 21_000,
+                "zap_pubkey": recipient,
+                "zap_relays": "wss://relay.example.com",
+            },
+        )
+
+    invoice = l2.rpc.call("invoice", [21_000, "nozap", "nozap"])["bolt11"]
+    with pytest.raises(
+        RpcError, match="only LNURLs and lightning addresses can be zapped"
+    ):
+        l1.rpc.call(
+            "payany",
+            {
+                "invstring": invoice,
+                "zap_pubkey": recipient,
+                "zap_relays": ["wss://relay.example.com"],
+            },
+        )
+
+    l1.rpc.call(
+        "xpay",
+        {
+            "invstring": lnurl_server["lnurl_zap"],
+            "amount_msat": 21_000,
+            "zap_pubkey": recipient,
+            "zap_event": "ab" * 32,
+            "zap_relays": ["wss://relay.example.com", "wss://relay2.example.com"],
+            "zap_content": "great post",
+        },
+    )
+    zap = lnurl_server["received_zaps"][-1]
+    assert zap["kind"] == 9734
+    assert zap["content"] == "great post"
+    assert ["p", recipient] in zap["tags"]
+    assert ["e", "ab" * 32] in zap["tags"]
+    assert ["amount", "21000"] in zap["tags"]
+    assert [
+        "relays",
+        "wss://relay.example.com",
+        "wss://relay2.example.com",
+    ] in zap["tags"]
+
+
+def test_withdraw(node_factory, get_plugin, lnurl_server):  # noqa: F811
+    opts = {"plugin": get_plugin, "log-level": "debug"}
+
+    l1 = node_factory.get_node(
+        options=opts,
+    )
+    l2 = lnurl_server["node"]
+    l1.fundchannel(l2, 1_000_000, wait_for_active=True)
+    l1.rpc.call("xpay", {"invstring": lnurl_server["lnurl"], "amount_msat": 500_000})
+
+    with pytest.raises(RpcError, match="Amount is above maximum withdrawable"):
+        l1.rpc.call("payany-withdraw", [lnurl_server["lnurl_withdraw"], 300_000])
+
+    result = l1.rpc.call(
+        "payany-withdraw",
+        {"lnurl": lnurl_server["lnurl_withdraw"], "amount_msat": 100_000},
+    )
+    assert result["amount_msat"] == 100_000
+    assert result["description"] == "pytest withdraw"
+    invoice = l1.rpc.call("waitinvoice", [result["label"]])
+    assert invoice["status"] == "paid"
+
+    lud17 = lnurl_server["base"].replace("http://", "lnurlw://")
+    result = l1.rpc.call(
+        "payany-withdraw",
+        {"lnurl": f"{lud17}/lnurl/withdraw", "label": "mywithdraw"},
+    )
+    assert result["label"] == "mywithdraw"
+    assert result["amount_msat"] == 200_000
+
+
+def test_auth(node_factory, get_plugin, lnurl_server):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(2, opts={"plugin": get_plugin})
+
+    result = l1.rpc.call("payany-auth", [lnurl_server["lnurl_auth"]])
+    assert result["domain"] == "127.0.0.1"
+    assert result["action"] == "login"
+    assert lnurl_server["auth_logins"] == [result["key"]]
+
+    # same node and domain must give the same linking key
+    lud17 = lnurl_server["base"].replace("http://", "keyauth://")
+    result_lud17 = l1.rpc.call(
+        "payany-auth",
+        {"lnurl": f"{lud17}/lnurl/auth?tag=login&k1={lnurl_server['auth_k1']}"},
+    )
+    assert result_lud17["key"] == result["key"]
+
+    result_l2 = l2.rpc.call("payany-auth", [lnurl_server["lnurl_auth"]])
+    assert result_l2["key"] != result["key"]
+
+    with pytest.raises(RpcError, match="Unknown k1"):
+        l1.rpc.call(
+            "payany-auth",
+            [f"{lud17}/lnurl/auth?tag=login&k1={'00' * 32}"],
+        )
+
+
+def test_channel(node_factory, get_plugin, lnurl_server):  # noqa: F811
+    l1 = node_factory.get_node(options={"plugin": get_plugin})
+    l2 = lnurl_server["node"]
+
+    result = l1.rpc.call("payany-channel", [lnurl_server["lnurl_channel"]])
+    assert result["peer_id"] == l2.info["id"]
+    assert result["private"] is False
+    assert lnurl_server["channel_requests"][-1]["remoteid"] == l1.info["id"]
+    assert lnurl_server["channel_requests"][-1]["private"] == "0"
+
+    lud17 = lnurl_server["base"].replace("http://", "lnurlc://")
+    result = l1.rpc.call(
+        "payany-channel", {"lnurl": f"{lud17}/lnurl/channel", "private": True}
+    )
+    assert result["private"] is True
+    assert lnurl_server["channel_requests"][-1]["private"] == "1"
+
+    with pytest.raises(RpcError, match="LNURL-channel"):
+        l1.rpc.call(
+            "xpay", {"invstring": lnurl_server["lnurl_channel"], "amount_msat": 1000}
+        )
+
+
 def test_pay_supercharged(
     node_factory,
     get_plugin,  # noqa: F811
@@ -98,7 +731,8 @@ def test_pay_supercharged(
     assert result["amount_msat"] == 3_000
     pay = l2.rpc.call("listinvoices", {})["invoices"]
     assert pay[0]["amount_msat"] == 3_000
-    assert pay[0]["description"] == "test3"
+    assert pay[0]["description"] == "pytest lnurl server"
+    assert lnurl_server["received_comments"][3_000] == "test3"
 
     result = l1.rpc.call("pay", [lnurl, 2_000])
     assert result["amount_msat"] == 2_000
@@ -137,7 +771,8 @@ async def test_renepay_supercharged(
     assert result["amount_msat"] == 3_000
     pay = l2.rpc.call("listinvoices", {})["invoices"]
     assert pay[0]["amount_msat"] == 3_000
-    assert pay[0]["description"] == "test3"
+    assert pay[0]["description"] == "pytest lnurl server"
+    assert lnurl_server["received_comments"][3_000] == "test3"
 
     result = l1.rpc.call("renepay", [lnurl, 2_000])
     assert result["amount_msat"] == 2_000